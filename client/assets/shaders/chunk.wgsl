@@ -0,0 +1,63 @@
+#import bevy_pbr::mesh_view_bind_group
+#import bevy_pbr::mesh_struct
+
+[[group(1), binding(0)]]
+var atlas_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var atlas_sampler: sampler;
+
+struct ChunkMaterial {
+  //Texels with a lower alpha are discarded, 0 for blended materials
+  alpha_cutoff: f32;
+};
+[[group(1), binding(2)]]
+var<uniform> material: ChunkMaterial;
+
+[[group(2), binding(0)]]
+var<uniform> mesh: Mesh;
+
+struct Vertex {
+  [[location(0)]] position: vec3<f32>;
+  [[location(1)]] uv: vec2<f32>;
+  [[location(2)]] tile: vec4<f32>;
+  [[location(3)]] color: vec4<f32>;
+};
+
+struct VertexOutput {
+  [[builtin(position)]] clip_position: vec4<f32>;
+  [[location(0)]] uv: vec2<f32>;
+  [[location(1)]] tile: vec4<f32>;
+  [[location(2)]] color: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex) -> VertexOutput {
+  let world_position = mesh.model * vec4<f32>(vertex.position, 1.0);
+  var out: VertexOutput;
+  out.clip_position = view.view_proj * world_position;
+  out.uv = vertex.uv;
+  out.tile = vertex.tile;
+  out.color = vertex.color;
+  return out;
+}
+
+struct FragmentInput {
+  [[location(0)]] uv: vec2<f32>;
+  [[location(1)]] tile: vec4<f32>;
+  [[location(2)]] color: vec4<f32>;
+};
+
+[[stage(fragment)]]
+fn fragment(input: FragmentInput) -> [[location(0)]] vec4<f32> {
+  //UVs are in block units, wrap them into the atlas region of the face
+  let wrapped = input.tile.xy + fract(input.uv) * input.tile.zw;
+  //Stay half a texel inside the tile so the filter never reaches into neighbouring tiles
+  let half_texel = 0.5 / vec2<f32>(textureDimensions(atlas_texture));
+  let uv = clamp(wrapped, input.tile.xy + half_texel, input.tile.xy + input.tile.zw - half_texel);
+  let color = textureSample(atlas_texture, atlas_sampler, uv) * input.color;
+  //Cuts out the empty parts of textures like flowers
+  if (color.a < material.alpha_cutoff) {
+    discard;
+  }
+  return color;
+}
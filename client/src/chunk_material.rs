@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+use bevy::{
+  ecs::system::{lifetimeless::SRes, SystemParamItem},
+  pbr::{MaterialPipeline, MaterialPlugin},
+  reflect::TypeUuid,
+  render::{
+    mesh::MeshVertexBufferLayout,
+    render_asset::{RenderAsset, RenderAssets, PrepareAssetError},
+    render_resource::{
+      BindGroup, BindGroupDescriptor, BindGroupEntry, BindingResource,
+      BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
+      SamplerBindingType, ShaderStages, TextureSampleType, TextureViewDimension,
+      RenderPipelineDescriptor, SpecializedMeshPipelineError,
+      BufferBindingType, BufferInitDescriptor, BufferUsages,
+    },
+    renderer::RenderDevice,
+  },
+};
+use crate::mesh_builder::{ATTRIBUTE_ATLAS_TILE, ATTRIBUTE_COLOR};
+
+//Material used by chunk meshes
+//Unlike StandardMaterial it can repeat a region of the texture atlas across merged faces
+//AlphaMode::Mask discards texels below the cutoff, so textures with holes can be drawn without blending
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "5b1e0a4c-3f7d-4c52-9a0e-8d2f61b7c934"]
+pub struct ChunkMaterial {
+  pub texture: Handle<Image>,
+  pub alpha_mode: AlphaMode,
+}
+
+pub struct GpuChunkMaterial {
+  bind_group: BindGroup,
+  alpha_mode: AlphaMode,
+}
+
+impl RenderAsset for ChunkMaterial {
+  type ExtractedAsset = ChunkMaterial;
+  type PreparedAsset = GpuChunkMaterial;
+  type Param = (SRes<RenderDevice>, SRes<MaterialPipeline<Self>>, SRes<RenderAssets<Image>>);
+
+  fn extract_asset(&self) -> Self::ExtractedAsset {
+    self.clone()
+  }
+
+  fn prepare_asset(
+    material: Self::ExtractedAsset,
+    (render_device, pipeline, images): &mut SystemParamItem<Self::Param>,
+  ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+    let image = match images.get(&material.texture) {
+      Some(image) => image,
+      None => return Err(PrepareAssetError::RetryNextUpdate(material))
+    };
+    let alpha_cutoff = match material.alpha_mode {
+      AlphaMode::Mask(cutoff) => cutoff,
+      _ => 0.
+    };
+    //Uniform buffers are padded to 16 bytes
+    let uniform: Vec<u8> = [alpha_cutoff, 0., 0., 0.].iter().flat_map(|x| x.to_le_bytes()).collect();
+    let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+      label: Some("chunk_material_uniform_buffer"),
+      contents: &uniform,
+      usage: BufferUsages::UNIFORM,
+    });
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+      label: Some("chunk_material_bind_group"),
+      layout: &pipeline.material_layout,
+      entries: &[
+        BindGroupEntry {
+          binding: 0,
+          resource: BindingResource::TextureView(&image.texture_view),
+        },
+        BindGroupEntry {
+          binding: 1,
+          resource: BindingResource::Sampler(&image.sampler),
+        },
+        BindGroupEntry {
+          binding: 2,
+          resource: buffer.as_entire_binding(),
+        },
+      ],
+    });
+    Ok(GpuChunkMaterial {
+      bind_group,
+      alpha_mode: material.alpha_mode,
+    })
+  }
+}
+
+impl Material for ChunkMaterial {
+  fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+    Some(asset_server.load("shaders/chunk.wgsl"))
+  }
+
+  fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+    Some(asset_server.load("shaders/chunk.wgsl"))
+  }
+
+  fn alpha_mode(material: &GpuChunkMaterial) -> AlphaMode {
+    material.alpha_mode
+  }
+
+  fn bind_group(material: &GpuChunkMaterial) -> &BindGroup {
+    &material.bind_group
+  }
+
+  fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      label: Some("chunk_material_layout"),
+      entries: &[
+        BindGroupLayoutEntry {
+          binding: 0,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Texture {
+            multisampled: false,
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2,
+          },
+          count: None,
+        },
+        BindGroupLayoutEntry {
+          binding: 1,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Sampler(SamplerBindingType::Filtering),
+          count: None,
+        },
+        BindGroupLayoutEntry {
+          binding: 2,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+          },
+          count: None,
+        },
+      ],
+    })
+  }
+
+  fn specialize(
+    descriptor: &mut RenderPipelineDescriptor,
+    layout: &MeshVertexBufferLayout,
+  ) -> Result<(), SpecializedMeshPipelineError> {
+    let vertex_layout = layout.get_layout(&[
+      Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+      Mesh::ATTRIBUTE_UV_0.at_shader_location(1),
+      ATTRIBUTE_ATLAS_TILE.at_shader_location(2),
+      ATTRIBUTE_COLOR.at_shader_location(3),
+    ])?;
+    descriptor.vertex.buffers = vec![vertex_layout];
+    Ok(())
+  }
+}
+
+pub struct ChunkMaterialPlugin;
+impl Plugin for ChunkMaterialPlugin {
+  fn build(&self, app: &mut App) {
+    app.add_plugin(MaterialPlugin::<ChunkMaterial>::default());
+  }
+}
@@ -0,0 +1,388 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use iyes_loopless::prelude::*;
+use std::{collections::VecDeque, sync::Arc};
+use shared::{
+  blocks::BlockTypeManager,
+  consts::{CHUNK_SIZE, CHUNK_HEIGHT, SECTION_HEIGHT, CHUNK_SECTIONS, MAX_LIGHT_LEVEL},
+  types::{
+    CubeFace,
+    chunk::{ChunkData, ChunkDataComponent, ChunkPosition, ChunkMap},
+  },
+};
+use crate::{
+  GameState,
+  networking::BlockUpdateEvt,
+  world::{DirtySections, for_each_affected_section},
+};
+
+//Lighting a new chunk is expensive, so only a few of them are lit every tick
+const MAX_LIT_CHUNKS_PER_TICK: usize = 4;
+const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_HEIGHT * CHUNK_SIZE;
+
+type BlockPosition = (i64, i64, i64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightChannel {
+  //Light coming from the sky, full sky light doesn't get weaker when going straight down
+  Sky,
+  //Light emitted by blocks
+  Block,
+}
+impl LightChannel {
+  pub const ALL: [LightChannel; 2] = [LightChannel::Sky, LightChannel::Block];
+
+  #[inline] fn shift(self) -> u8 {
+    match self {
+      LightChannel::Sky => 4,
+      LightChannel::Block => 0,
+    }
+  }
+}
+
+//Sky light is stored in the upper 4 bits, block light in the lower 4 bits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LightLevel(pub u8);
+impl LightLevel {
+  pub const SKY: Self = Self(MAX_LIGHT_LEVEL << 4);
+
+  #[inline] pub fn get(self, channel: LightChannel) -> u8 {
+    (self.0 >> channel.shift()) & 0xf
+  }
+  #[inline] pub fn with(self, channel: LightChannel, level: u8) -> Self {
+    let shift = channel.shift();
+    Self((self.0 & !(0xfu8 << shift)) | ((level & 0xf) << shift))
+  }
+  //Level of the brighter channel
+  #[inline] pub fn max(self) -> u8 {
+    self.get(LightChannel::Sky).max(self.get(LightChannel::Block))
+  }
+}
+
+//Light levels of every block in a chunk
+//Cloning is cheap, the data is only copied if it's modified while a mesh task still holds a copy
+#[derive(Clone)]
+pub struct ChunkLight(Arc<Vec<LightLevel>>);
+impl ChunkLight {
+  fn new() -> Self {
+    Self(Arc::new(vec![LightLevel::default(); CHUNK_VOLUME]))
+  }
+  #[inline] fn index(x: usize, y: usize, z: usize) -> usize {
+    (y * CHUNK_SIZE + z) * CHUNK_SIZE + x
+  }
+  #[inline] pub fn get(&self, x: usize, y: usize, z: usize) -> LightLevel {
+    self.0[Self::index(x, y, z)]
+  }
+  #[inline] fn set(&mut self, x: usize, y: usize, z: usize, level: LightLevel) {
+    Arc::make_mut(&mut self.0)[Self::index(x, y, z)] = level;
+  }
+}
+
+//Light of every chunk that has been lit
+//Chunks are lit by update_light after their data arrives and can't be meshed before that
+#[derive(Default)]
+pub struct LightMap(HashMap<ChunkPosition, ChunkLight>);
+impl LightMap {
+  #[inline] pub fn get(&self, position: ChunkPosition) -> Option<&ChunkLight> {
+    self.0.get(&position)
+  }
+  #[inline] pub fn remove(&mut self, position: ChunkPosition) {
+    self.0.remove(&position);
+  }
+}
+
+#[inline] fn offset((x, y, z): BlockPosition, face: CubeFace) -> BlockPosition {
+  let (dx, dy, dz) = face.offset();
+  (x + dx as i64, y + dy as i64, z + dz as i64)
+}
+
+//Flood fill across all lit chunks
+struct LightEngine<'a> {
+  lights: &'a mut LightMap,
+  blocks: &'a HashMap<ChunkPosition, ChunkData>,
+  block_types: &'a BlockTypeManager,
+  //Sections that have to be remeshed because their light changed
+  dirty: HashMap<ChunkPosition, DirtySections>,
+}
+impl<'a> LightEngine<'a> {
+  //Returns (is opaque, light emission), None if the block is not loaded
+  fn block(&self, (x, y, z): BlockPosition) -> Option<(bool, u8)> {
+    let (position, (bx, by, bz)) = ChunkPosition::from_block(x, y, z)?;
+    let block = self.blocks.get(&position)?.get(bx, by, bz);
+    let meta = self.block_types.get_by_state(block.state)?;
+    Some((meta.is_opaque(), meta.light_emission))
+  }
+
+  //None if the block is not lit yet
+  fn light(&self, (x, y, z): BlockPosition) -> Option<LightLevel> {
+    let (position, (bx, by, bz)) = ChunkPosition::from_block(x, y, z)?;
+    Some(self.lights.get(position)?.get(bx, by, bz))
+  }
+
+  fn set_light(&mut self, (x, y, z): BlockPosition, channel: LightChannel, level: u8) {
+    let (position, block) = match ChunkPosition::from_block(x, y, z) {
+      Some(located) => located,
+      None => return
+    };
+    let light = match self.lights.0.get_mut(&position) {
+      Some(light) => light,
+      None => return
+    };
+    let (bx, by, bz) = block;
+    let old = light.get(bx, by, bz);
+    light.set(bx, by, bz, old.with(channel, level));
+    let dirty = &mut self.dirty;
+    for_each_affected_section(position, block, |chunk, section| {
+      dirty.entry(chunk).or_default().mark(section);
+    });
+  }
+
+  //Spreads light of the queued blocks to their neighbours
+  fn spread(&mut self, mut queue: VecDeque<(LightChannel, BlockPosition)>) {
+    while let Some((channel, position)) = queue.pop_front() {
+      let level = match self.light(position) {
+        Some(light) => light.get(channel),
+        None => continue
+      };
+      if level <= 1 { continue }
+      for face in CubeFace::ALL {
+        let neighbour = offset(position, face);
+        let new_level = match (channel, face) {
+          (LightChannel::Sky, CubeFace::Bottom) if level == MAX_LIGHT_LEVEL => MAX_LIGHT_LEVEL,
+          _ => level - 1
+        };
+        match (self.block(neighbour), self.light(neighbour)) {
+          (Some((false, _)), Some(light)) if light.get(channel) < new_level => {
+            self.set_light(neighbour, channel, new_level);
+            queue.push_back((channel, neighbour));
+          },
+          _ => ()
+        }
+      }
+    }
+  }
+
+  //Removes light that came from the queued blocks, along with the level they had before it was removed
+  //Neighbours that are lit from somewhere else are added to `refill` to spread their light back
+  fn unspread(
+    &mut self,
+    mut queue: VecDeque<(LightChannel, BlockPosition, u8)>,
+    refill: &mut VecDeque<(LightChannel, BlockPosition)>
+  ) {
+    while let Some((channel, position, level)) = queue.pop_front() {
+      for face in CubeFace::ALL {
+        let neighbour = offset(position, face);
+        let neighbour_level = match self.light(neighbour) {
+          Some(light) => light.get(channel),
+          None => continue
+        };
+        if neighbour_level == 0 { continue }
+        let lit_from_here = neighbour_level < level || (
+          channel == LightChannel::Sky && face == CubeFace::Bottom && level == MAX_LIGHT_LEVEL
+        );
+        if !lit_from_here {
+          refill.push_back((channel, neighbour));
+          continue;
+        }
+        self.set_light(neighbour, channel, 0);
+        queue.push_back((channel, neighbour, neighbour_level));
+        //Light sources keep their own light
+        if channel == LightChannel::Block {
+          if let Some((_, emission)) = self.block(neighbour) {
+            if emission > 0 {
+              self.set_light(neighbour, channel, emission);
+              refill.push_back((channel, neighbour));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  //Relights the area around a block that has been changed
+  fn update_block(&mut self, position: BlockPosition) {
+    let (opaque, emission) = match self.block(position) {
+      Some(block) => block,
+      None => return
+    };
+    let old = match self.light(position) {
+      Some(light) => light,
+      None => return
+    };
+    let mut removed = VecDeque::new();
+    let mut refill = VecDeque::new();
+    for channel in LightChannel::ALL {
+      let level = old.get(channel);
+      if level > 0 {
+        self.set_light(position, channel, 0);
+        removed.push_back((channel, position, level));
+      }
+    }
+    self.unspread(removed, &mut refill);
+    if emission > 0 {
+      self.set_light(position, LightChannel::Block, emission);
+      refill.push_back((LightChannel::Block, position));
+    }
+    if !opaque {
+      //Nothing above the world can pass the sky light down
+      if position.1 == CHUNK_HEIGHT as i64 - 1 {
+        self.set_light(position, LightChannel::Sky, MAX_LIGHT_LEVEL);
+        refill.push_back((LightChannel::Sky, position));
+      }
+      for face in CubeFace::ALL {
+        let neighbour = offset(position, face);
+        if let Some(light) = self.light(neighbour) {
+          for channel in LightChannel::ALL {
+            if light.get(channel) > 0 {
+              refill.push_back((channel, neighbour));
+            }
+          }
+        }
+      }
+    }
+    self.spread(refill);
+  }
+
+  //Computes light of a chunk that has just been loaded and spreads light between it and its neighbours
+  fn light_chunk(&mut self, position: ChunkPosition) {
+    let blocks = self.blocks;
+    let block_types = self.block_types;
+    let data = match blocks.get(&position) {
+      Some(data) => data,
+      None => return
+    };
+    let meta = move |x: usize, y: usize, z: usize| {
+      block_types.get_by_state(data.get(x, y, z).state).expect("Invalid block state")
+    };
+    let origin = (position.0 * CHUNK_SIZE as i64, position.1 * CHUNK_SIZE as i64);
+    let world_position = move |x: usize, y: usize, z: usize| (origin.0 + x as i64, y as i64, origin.1 + z as i64);
+    let mut light = ChunkLight::new();
+    let mut queue = VecDeque::new();
+
+    //Sky light goes straight down until it hits an opaque block
+    let mut heights = [[0; CHUNK_SIZE]; CHUNK_SIZE];
+    for x in 0..CHUNK_SIZE {
+      for z in 0..CHUNK_SIZE {
+        let mut y = CHUNK_HEIGHT;
+        while y > 0 && !meta(x, y - 1, z).is_opaque() {
+          y -= 1;
+          light.set(x, y, z, LightLevel::SKY);
+        }
+        heights[x][z] = y;
+      }
+    }
+    //Only blocks next to a darker column can spread sky light sideways
+    //Columns on the border spread it to the neighbouring chunks
+    for x in 0..CHUNK_SIZE {
+      for z in 0..CHUNK_SIZE {
+        let height = |x: usize, z: usize| match x < CHUNK_SIZE && z < CHUNK_SIZE {
+          true => heights[x][z],
+          false => CHUNK_HEIGHT
+        };
+        let top = height(x.wrapping_sub(1), z)
+          .max(height(x + 1, z))
+          .max(height(x, z.wrapping_sub(1)))
+          .max(height(x, z + 1));
+        for y in heights[x][z]..top {
+          queue.push_back((LightChannel::Sky, world_position(x, y, z)));
+        }
+      }
+    }
+
+    //Light sources
+    for section in 0..CHUNK_SECTIONS {
+      if data.section(section).is_none() { continue }
+      for y in (section * SECTION_HEIGHT)..((section + 1) * SECTION_HEIGHT) {
+        for z in 0..CHUNK_SIZE {
+          for x in 0..CHUNK_SIZE {
+            let emission = meta(x, y, z).light_emission;
+            if emission > 0 {
+              light.set(x, y, z, light.get(x, y, z).with(LightChannel::Block, emission));
+              queue.push_back((LightChannel::Block, world_position(x, y, z)));
+            }
+          }
+        }
+      }
+    }
+    self.lights.0.insert(position, light);
+    self.dirty.insert(position, DirtySections::ALL);
+
+    //Light from neighbouring chunks comes in through the borders
+    const SIZE: i64 = CHUNK_SIZE as i64;
+    for i in 0..SIZE {
+      for (dx, dz) in [(-1, i), (SIZE, i), (i, -1), (i, SIZE)] {
+        for y in 0..CHUNK_HEIGHT as i64 {
+          let outside = (origin.0 + dx, y, origin.1 + dz);
+          if let Some(light) = self.light(outside) {
+            for channel in LightChannel::ALL {
+              if light.get(channel) > 1 {
+                queue.push_back((channel, outside));
+              }
+            }
+          }
+        }
+      }
+    }
+    self.spread(queue);
+  }
+}
+
+fn update_light(
+  mut light_map: ResMut<LightMap>,
+  mut block_updates: EventReader<BlockUpdateEvt>,
+  block_types: Res<BlockTypeManager>,
+  chunk_map: Res<ChunkMap>,
+  chunks: Query<(&ChunkPosition, &ChunkDataComponent)>,
+  mut dirty_sections: Query<&mut DirtySections>,
+) {
+  let updates: Vec<BlockPosition> = block_updates.iter().map(|event| event.position).collect();
+  let unlit: Vec<ChunkPosition> = chunks.iter()
+    .map(|(position, _)| *position)
+    .filter(|position| light_map.get(*position).is_none())
+    .take(MAX_LIT_CHUNKS_PER_TICK)
+    .collect();
+  if updates.is_empty() && unlit.is_empty() {
+    return;
+  }
+
+  //Cloning ChunkData is cheap
+  let blocks: HashMap<ChunkPosition, ChunkData> = chunks.iter()
+    .map(|(position, data)| (*position, data.0.clone()))
+    .collect();
+  let mut engine = LightEngine {
+    lights: &mut *light_map,
+    blocks: &blocks,
+    block_types: &*block_types,
+    dirty: HashMap::default(),
+  };
+  for position in unlit {
+    engine.light_chunk(position);
+  }
+  for position in updates {
+    engine.update_block(position);
+  }
+
+  for (position, sections) in engine.dirty {
+    if let Some(entity) = chunk_map.get(position) {
+      if let Ok(mut dirty) = dirty_sections.get_mut(entity) {
+        dirty.0 |= sections.0;
+      }
+    }
+  }
+}
+
+pub struct LightPlugin;
+impl Plugin for LightPlugin {
+  fn build(&self, app: &mut App) {
+    app.init_resource::<LightMap>();
+    //Block updates are applied to chunk data in WorldMain
+    app.add_system_set(
+      ConditionSet::new()
+        .label("WorldLight")
+        .after("WorldMain")
+        .run_in_state(GameState::InGame)
+        .with_system(update_light)
+        .into()
+    );
+  }
+}
@@ -3,9 +3,11 @@ use bevy::tasks::TaskPoolBuilder;
 use bevy_egui::EguiPlugin;
 
 use iyes_loopless::prelude::AppLooplessStateExt;
-use shared::blocks::BlockManagerPlugin;
+use shared::blocks::{BlockTypeManager, default_blocks_dir};
 
 pub(crate) mod mesh_builder;
+pub(crate) mod chunk_material;
+pub(crate) mod light;
 pub(crate) mod world;
 pub(crate) mod networking;
 pub(crate) mod assets;
@@ -15,6 +17,8 @@ pub(crate) mod main_menu;
 
 use networking::NetworkingPlugin;
 use world::WorldPlugin;
+use chunk_material::ChunkMaterialPlugin;
+use light::LightPlugin;
 use assets::AssetLoaderPlugin;
 use player::PlayerPlugin;
 use chat::ChatPlugin;
@@ -50,11 +54,20 @@ fn main() {
 
   app.add_loopless_state(GameState::MainMenu);
 
-  app.add_plugin(BlockManagerPlugin);
+  let blocks = match BlockTypeManager::load(&default_blocks_dir()) {
+    Ok(blocks) => blocks,
+    Err(error) => {
+      error!("{}", error);
+      std::process::exit(1);
+    }
+  };
+  app.insert_resource(blocks);
   app.add_plugin(PlayerPlugin);
   app.add_plugin(AssetLoaderPlugin);
   app.add_plugin(NetworkingPlugin);
+  app.add_plugin(ChunkMaterialPlugin);
   app.add_plugin(WorldPlugin);
+  app.add_plugin(LightPlugin);
   app.add_plugin(ChatPlugin);
   app.add_plugin(MainMenuPlugin);
   
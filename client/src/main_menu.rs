@@ -12,7 +12,7 @@ use shared::{
   utils::{check_username, check_password}, 
   consts::DEFAULT_PORT
 };
-use crate::{GameState, networking::ConnectionConfig};
+use crate::{GameState, networking::{ConnectionConfig, ConnectionError}};
 
 #[derive(Default, PartialEq)]
 #[non_exhaustive]
@@ -34,7 +34,8 @@ fn main_menu_gui(
   mut commands: Commands,
   mut egui_context: ResMut<EguiContext>,
   mut gui_state: ResMut<MainMenuGuiState>,
-  mut exit: EventWriter<bevy::app::AppExit>
+  mut exit: EventWriter<bevy::app::AppExit>,
+  conn_error: Option<Res<ConnectionError>>,
 ) {
   egui::Window::new("Main menu")
     .collapsible(false)
@@ -45,6 +46,12 @@ fn main_menu_gui(
     .show(egui_context.ctx_mut(), |ui| {
       ui.vertical_centered_justified(|ui| {
 
+        //Last connection error
+        if let Some(conn_error) = &conn_error {
+          ui.label(egui::RichText::new(&conn_error.0).color(Color32::LIGHT_RED));
+          ui.separator();
+        }
+
         //Stuff
         match gui_state.screen {
           MainMenuScreen::Main => {
@@ -123,6 +130,7 @@ fn main_menu_gui(
                   }
                 }
                 if proceed {
+                  commands.remove_resource::<ConnectionError>();
                   commands.insert_resource(ConnectionConfig {
                     addr: connect_addr,
                     username: gui_state.username.clone(),
@@ -134,6 +142,7 @@ fn main_menu_gui(
             });
 
             if ui.button("[DEBUG] Connect to localhost").clicked() {
+              commands.remove_resource::<ConnectionError>();
               commands.insert_resource(ConnectionConfig {
                 addr: SocketAddr::new([127, 0, 0, 1].into(), DEFAULT_PORT),
                 username: format!("Debug{}", thread_rng().gen_range(1000..=9999)),
@@ -1,6 +1,10 @@
 use bevy::prelude::*;
-use bevy::render::mesh::{PrimitiveTopology, Indices};
-use shared::types::CubeFace;
+use bevy::render::{
+  mesh::{PrimitiveTopology, Indices, MeshVertexAttribute},
+  render_resource::VertexFormat,
+};
+use shared::{types::CubeFace, consts::MAX_LIGHT_LEVEL};
+use std::f32::consts::FRAC_1_SQRT_2;
 
 const CUBE_FACE_VERTICES: [[[f32; 3]; 4]; 6] = [
   [[0., 1., 0.], [0., 1., 1.], [1., 1., 0.], [1., 1., 1.]],
@@ -18,25 +22,113 @@ pub const CUBE_FACE_NORMALS: [[f32; 3]; 6] = [
   [0., 0., 1.],
   [0., -1., 0.]
 ];
+//Axes of the block that the U and V texture coordinates follow on each face
+const CUBE_FACE_UV_AXES: [[usize; 2]; 6] = [
+  [0, 2],
+  [0, 1],
+  [2, 1],
+  [2, 1],
+  [0, 1],
+  [0, 2]
+];
+//Axis that each face is perpendicular to
+const CUBE_FACE_NORMAL_AXES: [usize; 6] = [1, 2, 0, 0, 2, 1];
+const UNIT_UVS: [[f32; 2]; 4] = [
+  [1., 1.],
+  [1., 0.],
+  [0., 1.],
+  [0., 0.],
+];
 pub const CUBE_INDICES: [u32; 6] = [0, 1, 2, 2, 1, 3];
+pub const CUBE_INDICES_FLIPPED: [u32; 6] = [0, 2, 1, 2, 3, 1];
+//Same winding as CUBE_INDICES, but split along the other diagonal
+pub const CUBE_INDICES_ROTATED: [u32; 6] = [0, 1, 3, 0, 3, 2];
+
+const CROSS_FACE_VERTICES: [[[f32; 3]; 4]; 2] = [
+  [[0., 0., 0.], [0., 1., 0.], [1., 0., 1.], [1., 1., 1.]],
+  [[0., 0., 1.], [0., 1., 1.], [1., 0., 0.], [1., 1., 0.]],
+];
+const CROSS_FACE_NORMALS: [[f32; 3]; 2] = [
+  [FRAC_1_SQRT_2, 0., -FRAC_1_SQRT_2],
+  [-FRAC_1_SQRT_2, 0., -FRAC_1_SQRT_2],
+];
+
+//Region of the texture atlas used by a face: [min.x, min.y, size.x, size.y]
+//UVs are in block units, the chunk shader wraps them into this region
+//which allows merged faces to repeat the texture
+pub type AtlasTile = [f32; 4];
+pub const ATTRIBUTE_ATLAS_TILE: MeshVertexAttribute = 
+  MeshVertexAttribute::new("AtlasTile", 284710583, VertexFormat::Float32x4);
+pub const ATTRIBUTE_COLOR: MeshVertexAttribute = 
+  MeshVertexAttribute::new("ChunkColor", 284710584, VertexFormat::Float32x4);
 
+//Ambient occlusion of each vertex of a face, from 0 (fully occluded) to 3 (not occluded)
+pub type FaceAo = [u8; 4];
+pub const NO_AO: FaceAo = [3; 4];
+const AO_BRIGHTNESS: [f32; 4] = [0.4, 0.6, 0.8, 1.0];
+
+//Brightness of a light level, each level is 20% darker than the one above it
+//Completely dark blocks are still slightly visible
+fn light_brightness(level: u8) -> f32 {
+  const MIN_BRIGHTNESS: f32 = 0.05;
+  MIN_BRIGHTNESS + (1. - MIN_BRIGHTNESS) * 0.8f32.powi((MAX_LIGHT_LEVEL - level.min(MAX_LIGHT_LEVEL)) as i32)
+}
+
+fn vertex_colors(ao: FaceAo, light: u8) -> [[f32; 4]; 4] {
+  let light = light_brightness(light);
+  ao.map(|x| {
+    let brightness = AO_BRIGHTNESS[x as usize] * light;
+    [brightness, brightness, brightness, 1.]
+  })
+}
+
+//Offsets (relative to the block) of the blocks that occlude each vertex of a face:
+//the two blocks along the edges of the face and the one in the corner
+pub fn vertex_ao_neighbours(face: CubeFace) -> [[[i8; 3]; 3]; 4] {
+  let face_index = face as usize;
+  let normal = CUBE_FACE_NORMALS[face_index].map(|x| x as i8);
+  let [u_axis, v_axis] = CUBE_FACE_UV_AXES[face_index];
+  CUBE_FACE_VERTICES[face_index].map(|vertex| {
+    let direction = |axis: usize| if vertex[axis] > 0.5 { 1 } else { -1 };
+    let mut side_u = normal;
+    side_u[u_axis] = direction(u_axis);
+    let mut side_v = normal;
+    side_v[v_axis] = direction(v_axis);
+    let mut corner = side_u;
+    corner[v_axis] = side_v[v_axis];
+    [side_u, side_v, corner]
+  })
+}
+
+pub fn vertex_ao(side_u: bool, side_v: bool, corner: bool) -> u8 {
+  //If both sides are occluded the corner can't be seen anyway
+  if side_u && side_v {
+    return 0;
+  }
+  3 - side_u as u8 - side_v as u8 - corner as u8
+}
+
+//Something that cube faces can be added to
+//`light` is the light level of the block the face is pointing towards
+pub trait FaceSink {
+  fn add_face(&mut self, face: CubeFace, coord: [u8; 3], tile: AtlasTile, ao: FaceAo, light: u8);
+}
 
 #[derive(Default)]
 pub struct MeshBuilder {
   vertices: Vec<[f32; 3]>,
   normals: Vec<[f32; 3]>,
   uvs: Vec<[f32; 2]>,
+  tiles: Vec<AtlasTile>,
+  colors: Vec<[f32; 4]>,
   indices: Vec<u32>,
   faces: u32
 }
 impl MeshBuilder {
-  pub fn add_face(&mut self, face: CubeFace, coord: [u8; 3], uvs: [[f32; 2]; 4]) {
-    //Get face index from Face
-    let face_index = face as usize;
-    
+  fn add_quad(&mut self, vertices: [[f32; 3]; 4], normal: [f32; 3], indices: [u32; 6], coord: [u8; 3], uvs: [[f32; 2]; 4], tile: AtlasTile, colors: [[f32; 4]; 4]) {
     //Vertices
     self.vertices.extend_from_slice(
-      &CUBE_FACE_VERTICES[face_index].map(|mut vert| {
+      &vertices.map(|mut vert| {
         vert[0] += coord[0] as f32;
         vert[1] += coord[1] as f32;
         vert[2] += coord[2] as f32;
@@ -46,25 +138,64 @@ impl MeshBuilder {
 
     //Indices
     self.indices.extend_from_slice(
-      &CUBE_INDICES.map(|x| {
+      &indices.map(|x| {
         x + self.faces
       })
     );
 
     //Normals
     self.normals.extend(
-      std::iter::repeat(CUBE_FACE_NORMALS[face_index]).take(4)
+      std::iter::repeat(normal).take(4)
     );
 
     //UVs
     self.uvs.extend_from_slice(&uvs);
+    self.tiles.extend(
+      std::iter::repeat(tile).take(4)
+    );
+
+    //Colors
+    self.colors.extend_from_slice(&colors);
 
     //Increment face counter
     self.faces += 4;
   }
 
-  pub fn add_face_if(&mut self, condition: bool, face: CubeFace, coord: [u8; 3], uvs: [[f32; 2]; 4]) {
-    if condition { self.add_face(face, coord, uvs) }
+  //Face stretched over `size` blocks, size along the normal axis is ignored
+  pub fn add_face_sized(&mut self, face: CubeFace, coord: [u8; 3], size: [u8; 3], tile: AtlasTile, ao: FaceAo, light: u8) {
+    //Get face index from Face
+    let face_index = face as usize;
+    let mut size = size.map(|x| x as f32);
+    size[CUBE_FACE_NORMAL_AXES[face_index]] = 1.;
+    let [u_axis, v_axis] = CUBE_FACE_UV_AXES[face_index];
+    let mut uvs = UNIT_UVS;
+    for uv in uvs.iter_mut() {
+      uv[0] *= size[u_axis];
+      uv[1] *= size[v_axis];
+    }
+    //Split the quad along the brighter diagonal, otherwise AO looks different depending on face orientation
+    let indices = match ao[0] + ao[3] > ao[1] + ao[2] {
+      true => CUBE_INDICES_ROTATED,
+      false => CUBE_INDICES
+    };
+    self.add_quad(
+      CUBE_FACE_VERTICES[face_index].map(|vert| [vert[0] * size[0], vert[1] * size[1], vert[2] * size[2]]), 
+      CUBE_FACE_NORMALS[face_index], 
+      indices, coord, uvs, tile, vertex_colors(ao, light)
+    );
+  }
+
+  //Two diagonal quads, each one is added twice (with flipped winding) so they're visible from both sides
+  pub fn add_cross(&mut self, coord: [u8; 3], tile: AtlasTile, light: u8) {
+    let colors = vertex_colors(NO_AO, light);
+    for (vertices, normal) in CROSS_FACE_VERTICES.into_iter().zip(CROSS_FACE_NORMALS) {
+      self.add_quad(vertices, normal, CUBE_INDICES, coord, UNIT_UVS, tile, colors);
+      self.add_quad(vertices, normal.map(|x| -x), CUBE_INDICES_FLIPPED, coord, UNIT_UVS, tile, colors);
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.faces == 0
   }
 
   pub fn build(self) -> Mesh {
@@ -72,7 +203,186 @@ impl MeshBuilder {
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.vertices);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals);
     mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs);
+    mesh.insert_attribute(ATTRIBUTE_ATLAS_TILE, self.tiles);
+    mesh.insert_attribute(ATTRIBUTE_COLOR, self.colors);
     mesh.set_indices(Some(Indices::U32(self.indices)));
     mesh
   }
 }
+impl FaceSink for MeshBuilder {
+  fn add_face(&mut self, face: CubeFace, coord: [u8; 3], tile: AtlasTile, ao: FaceAo, light: u8) {
+    self.add_face_sized(face, coord, [1, 1, 1], tile, ao, light);
+  }
+}
+
+//Collects visible faces, then merges coplanar faces with the same texture and light into larger quads
+//Only faces with uniform AO are merged, as AO can't be interpolated correctly across larger quads
+pub struct GreedyFaces {
+  size: [usize; 3],
+  //One grid per face direction, indexed by block coordinates
+  faces: [Vec<Option<(AtlasTile, FaceAo, u8)>>; 6],
+}
+impl GreedyFaces {
+  pub fn new(size: [usize; 3]) -> Self {
+    let volume = size[0] * size[1] * size[2];
+    Self {
+      size,
+      faces: [(); 6].map(|_| vec![None; volume]),
+    }
+  }
+
+  #[inline] fn index(&self, coord: [usize; 3]) -> usize {
+    (coord[0] * self.size[1] + coord[1]) * self.size[2] + coord[2]
+  }
+
+  pub fn build_into(mut self, builder: &mut MeshBuilder) {
+    for face in CubeFace::ALL {
+      let face_index = face as usize;
+      let normal_axis = CUBE_FACE_NORMAL_AXES[face_index];
+      let [u_axis, v_axis] = CUBE_FACE_UV_AXES[face_index];
+      let coord_at = |layer: usize, u: usize, v: usize| {
+        let mut coord = [0; 3];
+        coord[normal_axis] = layer;
+        coord[u_axis] = u;
+        coord[v_axis] = v;
+        coord
+      };
+      for layer in 0..self.size[normal_axis] {
+        for v in 0..self.size[v_axis] {
+          let mut u = 0;
+          while u < self.size[u_axis] {
+            let index = self.index(coord_at(layer, u, v));
+            let (tile, ao, light) = match self.faces[face_index][index] {
+              Some(face) => face,
+              None => { u += 1; continue }
+            };
+            let key = Some((tile, ao, light));
+            let mergeable = ao.iter().all(|&x| x == ao[0]);
+            //Grow along U...
+            let mut width = 1;
+            while mergeable && u + width < self.size[u_axis] 
+              && self.faces[face_index][self.index(coord_at(layer, u + width, v))] == key {
+              width += 1;
+            }
+            //...then along V, as long as the whole row matches
+            let mut height = 1;
+            while mergeable && v + height < self.size[v_axis] && (0..width).all(|du| {
+              self.faces[face_index][self.index(coord_at(layer, u + du, v + height))] == key
+            }) {
+              height += 1;
+            }
+            //Remove merged faces so they're not emitted again
+            for dv in 0..height {
+              for du in 0..width {
+                let index = self.index(coord_at(layer, u + du, v + dv));
+                self.faces[face_index][index] = None;
+              }
+            }
+            let mut size = [1u8; 3];
+            size[u_axis] = width as u8;
+            size[v_axis] = height as u8;
+            builder.add_face_sized(face, coord_at(layer, u, v).map(|x| x as u8), size, tile, ao, light);
+            u += width;
+          }
+        }
+      }
+    }
+  }
+}
+impl FaceSink for GreedyFaces {
+  fn add_face(&mut self, face: CubeFace, coord: [u8; 3], tile: AtlasTile, ao: FaceAo, light: u8) {
+    let index = self.index(coord.map(|x| x as usize));
+    self.faces[face as usize][index] = Some((tile, ao, light));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TILE: AtlasTile = [0., 0., 0.25, 0.25];
+  const LIGHT: u8 = MAX_LIGHT_LEVEL;
+
+  fn quad_count(builder: &MeshBuilder) -> usize {
+    builder.vertices.len() / 4
+  }
+
+  //Min and max corners of all vertices in the mesh
+  fn bounds(builder: &MeshBuilder) -> ([f32; 3], [f32; 3]) {
+    builder.vertices.iter().fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), vertex| (
+      [0, 1, 2].map(|axis| min[axis].min(vertex[axis])),
+      [0, 1, 2].map(|axis| max[axis].max(vertex[axis])),
+    ))
+  }
+
+  #[test]
+  fn flat_terrain_is_a_single_quad() {
+    let mut faces = GreedyFaces::new([16, 1, 16]);
+    for x in 0..16 {
+      for z in 0..16 {
+        faces.add_face(CubeFace::Top, [x, 0, z], TILE, NO_AO, LIGHT);
+      }
+    }
+    let mut builder = MeshBuilder::default();
+    faces.build_into(&mut builder);
+    assert_eq!(quad_count(&builder), 1);
+    assert_eq!(bounds(&builder), ([0., 1., 0.], [16., 1., 16.]));
+  }
+
+  #[test]
+  fn merged_quad_covers_exactly_its_faces() {
+    let mut faces = GreedyFaces::new([16, 1, 16]);
+    for x in 2..6 {
+      for z in 3..8 {
+        faces.add_face(CubeFace::Top, [x, 0, z], TILE, NO_AO, LIGHT);
+      }
+    }
+    let mut builder = MeshBuilder::default();
+    faces.build_into(&mut builder);
+    assert_eq!(quad_count(&builder), 1);
+    assert_eq!(bounds(&builder), ([2., 1., 3.], [6., 1., 8.]));
+    //UVs are in block units, so the texture repeats across the quad
+    let max_uv = builder.uvs.iter().fold([0f32; 2], |max, uv| [max[0].max(uv[0]), max[1].max(uv[1])]);
+    assert_eq!(max_uv, [4., 5.]);
+  }
+
+  #[test]
+  fn different_faces_are_not_merged() {
+    let mut faces = GreedyFaces::new([4, 1, 1]);
+    faces.add_face(CubeFace::Top, [0, 0, 0], TILE, NO_AO, LIGHT);
+    faces.add_face(CubeFace::Top, [1, 0, 0], TILE, NO_AO, LIGHT - 1);
+    faces.add_face(CubeFace::Top, [2, 0, 0], [0.25, 0., 0.25, 0.25], NO_AO, LIGHT);
+    let mut builder = MeshBuilder::default();
+    faces.build_into(&mut builder);
+    assert_eq!(quad_count(&builder), 3);
+  }
+
+  #[test]
+  fn per_vertex_ao_prevents_merging() {
+    let ao = [3, 3, 1, 1];
+    let mut faces = GreedyFaces::new([4, 1, 4]);
+    for x in 0..4 {
+      for z in 0..4 {
+        faces.add_face(CubeFace::Top, [x, 0, z], TILE, ao, LIGHT);
+      }
+    }
+    let mut builder = MeshBuilder::default();
+    faces.build_into(&mut builder);
+    assert_eq!(quad_count(&builder), 16);
+    assert_eq!(bounds(&builder), ([0., 1., 0.], [4., 1., 4.]));
+  }
+
+  #[test]
+  fn uniform_ao_is_merged() {
+    let ao = [1; 4];
+    let mut faces = GreedyFaces::new([4, 1, 4]);
+    for x in 0..4 {
+      for z in 0..4 {
+        faces.add_face(CubeFace::Top, [x, 0, z], TILE, ao, LIGHT);
+      }
+    }
+    let mut builder = MeshBuilder::default();
+    faces.build_into(&mut builder);
+    assert_eq!(quad_count(&builder), 1);
+  }
+}
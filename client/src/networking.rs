@@ -2,6 +2,7 @@ use bevy::prelude::*;
 use iyes_loopless::prelude::*;
 
 use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::HashMap;
 use bevy_renet::{
   RenetClientPlugin,
   renet::{
@@ -22,23 +23,28 @@ use std::{
 use shared::{
   types::{
     net::Lobby, 
-    player::{Username, PlayerInitData},
-    chunk::{Chunk, ChunkPosition, ChunkDataComponent},
+    block::Block,
+    player::{Username, PlayerInitData, Orientation},
+    chunk::{Chunk, ChunkData, ChunkPosition, ChunkMap, ChunkDataComponent, ChunkDecodeError},
   },
+  blocks::BlockTypeManager,
   messages::{
     ClientToServerMessages, 
     ServerToClientMessages,
   },
   consts::{
-    CHANNEL_RELIABLE, CHANNEL_UNRELIABLE,
+    CHANNEL_RELIABLE, CHANNEL_UNRELIABLE, CHANNEL_BLOCK,
+    DEFAULT_CLIENT_VIEW_DIST,
     renet_connection_config
   },
 };
 use crate::{
   GameState,
   chat::ChatMessages,
-  player::{ChunkLocation, NetPlayer, Player},
+  player::{ChunkLocation, NetPlayer, Player, PlayerController, InterpolationBuffer, NetPlayerSnapshot},
   player::MainPlayer,
+  world::{DirtySections, adjacent_chunks},
+  light::LightMap,
 };
 
 #[derive(Clone, Copy, Debug)]
@@ -53,14 +59,81 @@ impl From<ChunkLocation> for RequestChunk {
 #[derive(Clone, Debug)]
 pub struct RequestNetChatSend(pub String);
 
+//Block positions are in world coordinates
+#[derive(Clone, Copy, Debug)]
+pub enum RequestBlockChange {
+  Break { position: (i64, i64, i64) },
+  Place { position: (i64, i64, i64), block: Block },
+}
+
+//Server-confirmed block change, already remapped to local state ids
+#[derive(Clone, Copy, Debug)]
+pub struct BlockUpdateEvt {
+  pub position: (i64, i64, i64),
+  pub block: Block,
+}
+
+//The server stopped streaming the chunk to us
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkUnloadEvt(pub ChunkPosition);
+
 #[derive(Clone, Debug)]
 pub struct AddNetPlayer{
   pub client_id: u64,
   pub init_data: PlayerInitData
 }
 
+//Give up on a chunk after this many failed attempts to decode it
+const MAX_CHUNK_RETRIES: u32 = 3;
+
 #[derive(Component)]
-pub struct DecompressTask(pub Task<ChunkDataComponent>);
+pub struct DecompressTask {
+  pub task: Task<Result<ChunkData, ChunkDecodeError>>,
+  //Block updates received while decompressing, applied on top of the new data
+  pub queued: Vec<((usize, usize, usize), Block)>,
+}
+impl DecompressTask {
+  pub fn new(task: Task<Result<ChunkData, ChunkDecodeError>>) -> Self {
+    Self { task, queued: Vec::new() }
+  }
+}
+
+//Maps server block indices to local ones (and back)
+#[derive(Clone, Debug)]
+pub struct BlockRemap {
+  pub table: Vec<u16>,
+  pub inverse: Vec<u16>,
+  pub identity: bool,
+}
+impl BlockRemap {
+  pub fn new(table: Vec<u16>) -> Self {
+    let identity = table.iter().enumerate().all(|(index, &block)| index == block as usize);
+    //Local states that don't exist on the server become air
+    let mut inverse = vec![0; table.iter().max().map_or(0, |&max| max as usize + 1)];
+    for (server, &local) in table.iter().enumerate() {
+      inverse[local as usize] = server as u16;
+    }
+    Self { table, inverse, identity }
+  }
+
+  pub fn to_local(&self, block: Block) -> Block {
+    match self.identity {
+      true => block,
+      false => Block { state: self.table.get(block.state as usize).copied().unwrap_or(0) }
+    }
+  }
+
+  pub fn to_server(&self, block: Block) -> Block {
+    match self.identity {
+      true => block,
+      false => Block { state: self.inverse.get(block.state as usize).copied().unwrap_or(0) }
+    }
+  }
+}
+
+//Reason of the last failed connection attempt, displayed in the main menu
+#[derive(Clone, Debug)]
+pub struct ConnectionError(pub String);
 
 fn run_if_client_conected(client: Option<Res<RenetClient>>) -> bool {
   if let Some(client) = client {
@@ -144,16 +217,27 @@ fn handle_incoming_stuff(
   mut client: ResMut<RenetClient>,
   pool: Res<AsyncComputeTaskPool>,
   mut chat: ResMut<ChatMessages>,
-  mut main_plr: Query<(Entity, &mut Transform), (With<MainPlayer>, Without<NetPlayer>)>,
+  mut main_plr: Query<(Entity, &mut Transform, Option<&mut PlayerController>), (With<MainPlayer>, Without<NetPlayer>)>,
   mut add_net_plr: EventWriter<AddNetPlayer>,
-  mut net_plr_trans: Query<&mut Transform, (Without<MainPlayer>, With<NetPlayer>)>,
-  lobby: ResMut<Lobby>,
+  mut block_updates: EventWriter<BlockUpdateEvt>,
+  mut chunk_unloads: EventWriter<ChunkUnloadEvt>,
+  mut net_plr_snapshots: EventWriter<NetPlayerSnapshot>,
+  blocks: Res<BlockTypeManager>,
+  remap: Option<Res<BlockRemap>>,
+  mut chunk_map: ResMut<ChunkMap>,
+  mut decompress_tasks: Query<&mut DecompressTask>,
 ) {
   if !client.is_connected() { return; }
 
   let mut main_plr = main_plr.single_mut();
+  let mut remap: Option<BlockRemap> = remap.map(|remap| remap.clone());
   
   for channel_id in 0..=2 {
+    //Chunks and block updates can't be read without the block palette,
+    //they're left in the channel until InitData arrives, even if it got delayed by packet loss
+    if channel_id == CHANNEL_BLOCK && remap.is_none() {
+      continue;
+    }
     while let Some(message) = client.receive_message(channel_id) {
       if let Ok(message) = bincode::deserialize(&message) {
         match message {
@@ -166,8 +250,25 @@ fn handle_incoming_stuff(
           ServerToClientMessages::InitData { 
             self_init, 
             player_init, 
-            chat_messages 
+            chat_messages,
+            block_palette,
           } => {
+            //Build block remap table
+            match blocks.remap_table(&block_palette) {
+              Ok(table) => {
+                let new_remap = BlockRemap::new(table);
+                commands.insert_resource(new_remap.clone());
+                remap = Some(new_remap);
+              },
+              Err(missing) => {
+                let reason = format!("Server block palette is incompatible, missing blocks: {}", missing.join(", "));
+                error!("{}", reason);
+                commands.insert_resource(ConnectionError(reason));
+                commands.insert_resource(NextState(GameState::MainMenu));
+                return;
+              }
+            }
+
             //Apply self_init
             commands.entity(main_plr.0).insert(Username(self_init.username));
             main_plr.1.translation = self_init.position;
@@ -187,22 +288,77 @@ fn handle_incoming_stuff(
             }
           },
 
-          ServerToClientMessages::PlayerSync { id, new_pos } => {
-            if let Some(net_plr_entity) = lobby.players.get(&id) {
-              net_plr_trans.get_mut(*net_plr_entity).unwrap().translation = new_pos;
+          ServerToClientMessages::PositionCorrection { position } => {
+            warn!("Server corrected player position to {}", position);
+            main_plr.1.translation = position;
+            if let Some(controller) = main_plr.2.as_mut() {
+              controller.velocity = Vec3::ZERO;
             }
+          },
+
+          ServerToClientMessages::PlayerSync { id, new_pos, orientation, tick } => {
+            net_plr_snapshots.send(NetPlayerSnapshot {
+              client_id: id,
+              position: new_pos,
+              orientation,
+              tick,
+            });
           }
 
           ServerToClientMessages::ChunkData { data, position } => {
             let position = ChunkPosition(position.0, position.1);
             info!("Chunk {:?} - Received", position);
+            let remap = match &remap {
+              Some(remap) => remap.clone(),
+              None => {
+                warn!("Chunk {:?} - Received before the block palette, ignoring", position);
+                continue;
+              }
+            };
             let task = pool.spawn(async move {
-              ChunkDataComponent((data).into())
+              let mut chunk = ChunkData::try_from(data)?;
+              if !remap.identity {
+                chunk.remap(&remap.table);
+              }
+              Ok(chunk)
             });
-            commands.spawn()
-              .insert(position)
-              .insert(Chunk)
-              .insert(DecompressTask(task));
+            match chunk_map.get(position) {
+              //Chunk already exists, its data gets replaced once decompressed
+              //This cancels the previous task if there is one, block updates queued on it are applied to the new data instead
+              Some(entity) => {
+                let mut new_task = DecompressTask::new(task);
+                if let Ok(mut old_task) = decompress_tasks.get_mut(entity) {
+                  new_task.queued = std::mem::take(&mut old_task.queued);
+                }
+                commands.entity(entity).insert(new_task);
+              },
+              None => {
+                let entity = commands.spawn()
+                  .insert(position)
+                  .insert(Chunk)
+                  .insert(DirtySections::default())
+                  .insert(DecompressTask::new(task))
+                  .id();
+                chunk_map.insert(position, entity);
+              }
+            }
+          },
+
+          ServerToClientMessages::UnloadChunk { position } => {
+            chunk_unloads.send(ChunkUnloadEvt(ChunkPosition(position.0, position.1)));
+          },
+
+          ServerToClientMessages::ViewDistance { distance } => {
+            info!("Server view distance: {}", distance);
+          },
+
+          ServerToClientMessages::BlockUpdate { position, block } => {
+            if let Some(remap) = &remap {
+              block_updates.send(BlockUpdateEvt { 
+                position, 
+                block: remap.to_local(block)
+              });
+            }
           },
 
           ServerToClientMessages::ChatMessage { message: chat_message } => { 
@@ -232,6 +388,7 @@ fn add_net_player_event_handler(
       .insert(NetPlayer)
       .insert(Player)
       .insert(Username(event.init_data.username.clone()))
+      .insert(InterpolationBuffer::new(event.init_data.position, event.init_data.orientation))
       .insert_bundle(PbrBundle {
         mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
         material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
@@ -242,6 +399,17 @@ fn add_net_player_event_handler(
   }
 }
 
+fn send_view_distance(
+  mut client: ResMut<RenetClient>,
+) {
+  client.send_message(
+    CHANNEL_RELIABLE,
+    bincode::serialize(
+      &ClientToServerMessages::SetViewDistance { distance: DEFAULT_CLIENT_VIEW_DIST }
+    ).unwrap()
+  );
+}
+
 pub fn request_chunks(
   mut events: EventReader<RequestChunk>,
   mut client: ResMut<RenetClient>,
@@ -258,6 +426,27 @@ pub fn request_chunks(
   }
 }
 
+pub fn send_block_changes(
+  mut events: EventReader<RequestBlockChange>,
+  mut client: ResMut<RenetClient>,
+  remap: Option<Res<BlockRemap>>,
+) {
+  let remap = match remap {
+    Some(remap) => remap,
+    None => return
+  };
+  for event in events.iter() {
+    let message = match *event {
+      RequestBlockChange::Break { position } => ClientToServerMessages::BreakBlock { position },
+      RequestBlockChange::Place { position, block } => ClientToServerMessages::PlaceBlock {
+        position,
+        block: remap.to_server(block)
+      },
+    };
+    client.send_message(CHANNEL_RELIABLE, bincode::serialize(&message).unwrap());
+  }
+}
+
 pub fn chat_send(
   mut events: EventReader<RequestNetChatSend>,
   mut client: ResMut<RenetClient>,
@@ -274,30 +463,87 @@ pub fn chat_send(
 
 pub fn sync_player(
   mut client: ResMut<RenetClient>,
+  mut sequence: Local<u32>,
   player: Query<&GlobalTransform, (With<MainPlayer>, Changed<GlobalTransform>)>
 ) {
-  let player = player.single();
+  let player = match player.get_single() {
+    Ok(player) => player,
+    Err(_) => return
+  };
+  *sequence = sequence.wrapping_add(1);
   client.send_message(
     CHANNEL_UNRELIABLE, 
     bincode::serialize(&ClientToServerMessages::PlayerMove {
-      new_pos: player.translation
+      new_pos: player.translation,
+      orientation: Orientation::from_rotation(player.rotation),
+      sequence: *sequence,
     }).unwrap()
   );
 }
 
 pub fn apply_decompress_tasks(
   mut commands: Commands,
-  mut query: Query<(Entity, &mut DecompressTask, &ChunkPosition)>,
+  mut query: Query<(Entity, &mut DecompressTask, &ChunkPosition, Option<&mut ChunkDataComponent>, &mut DirtySections)>,
+  mut neighbours: Query<&mut DirtySections, Without<DecompressTask>>,
+  mut chunk_map: ResMut<ChunkMap>,
+  mut light_map: ResMut<LightMap>,
+  mut ev_request: EventWriter<RequestChunk>,
+  mut retries: Local<HashMap<ChunkPosition, u32>>,
 ) {
-  //TODO Update chunks instead of duplicating!
-  query.for_each_mut(|(entity, mut task, position)| {
-    if let Some(chunk) = future::block_on(future::poll_once(&mut task.0)) {
-      commands.entity(entity)
-        .remove::<DecompressTask>()
-        .insert(chunk);
-        info!("Chunk {:?} - Decompressed", position);
+  let mut replaced = Vec::new();
+  query.for_each_mut(|(entity, mut task, position, data, mut dirty)| {
+    if let Some(result) = future::block_on(future::poll_once(&mut task.task)) {
+      commands.entity(entity).remove::<DecompressTask>();
+      match result {
+        Ok(mut chunk) => {
+          retries.remove(position);
+          for &((x, y, z), block) in task.queued.iter() {
+            chunk.set(x, y, z, block);
+          }
+          //Replace the data of existing chunks in place, it has to be lit again
+          match data {
+            Some(mut data) => {
+              data.0 = chunk;
+              light_map.remove(*position);
+              replaced.push(*position);
+            },
+            None => { commands.entity(entity).insert(ChunkDataComponent(chunk)); }
+          }
+          *dirty = DirtySections::ALL;
+          info!("Chunk {:?} - Decompressed", position);
+        },
+        Err(error) => {
+          //Keep the old data if there is any
+          if data.is_none() {
+            commands.entity(entity).despawn();
+            chunk_map.remove(*position);
+          }
+          let attempts = retries.entry(*position).or_insert(0);
+          *attempts += 1;
+          if *attempts > MAX_CHUNK_RETRIES {
+            error!("Chunk {:?} - Failed to decode: {}, giving up", position, error);
+            retries.remove(position);
+          } else {
+            error!("Chunk {:?} - Failed to decode: {}, requesting again", position, error);
+            ev_request.send((*position).into());
+          }
+        }
+      }
     }
   });
+
+  //New chunks are handled by mark_neighbours_of_new_chunks, but that doesn't see data replaced in place
+  //Light crosses chunk borders, so the neighbours are lit again too
+  for position in replaced {
+    for neighbour in adjacent_chunks(position) {
+      light_map.remove(neighbour);
+      if let Some(entity) = chunk_map.get(neighbour) {
+        if let Ok(mut dirty) = neighbours.get_mut(entity) {
+          *dirty = DirtySections::ALL;
+        }
+      }
+    }
+  }
 }
 
 const VIS_T: usize = 200;
@@ -329,6 +575,7 @@ fn disconnect(
     client.disconnect();
   }
   commands.remove_resource::<Lobby>();
+  commands.remove_resource::<BlockRemap>();
   commands.remove_resource::<RenetClient>();
   commands.remove_resource::<RenetClientVisualizer<VIS_T>>();
 }
@@ -348,6 +595,9 @@ impl Plugin for NetworkingPlugin {
     app.add_event::<RequestNetChatSend>();
     app.add_event::<RequestChunk>();
     app.add_event::<AddNetPlayer>();
+    app.add_event::<RequestBlockChange>();
+    app.add_event::<BlockUpdateEvt>();
+    app.add_event::<ChunkUnloadEvt>();
 
     app.add_plugin(RenetClientPlugin);
 
@@ -376,6 +626,7 @@ impl Plugin for NetworkingPlugin {
         )
         .with_system(request_chunks)
         .with_system(chat_send)
+        .with_system(send_block_changes)
         .with_system(apply_decompress_tasks)
         .with_system(sync_player)
         .into()
@@ -389,6 +640,7 @@ impl Plugin for NetworkingPlugin {
         .into()
     );
     
+    app.add_enter_system(GameState::InGame, send_view_distance);
     app.add_exit_system(GameState::InGame, disconnect);
 
   }
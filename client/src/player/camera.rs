@@ -2,6 +2,7 @@
 
 use bevy::prelude::*;
 use iyes_loopless::prelude::*;
+use super::cursor_locked;
 
 #[derive(Component)] 
 pub struct Camera {
@@ -20,12 +21,6 @@ impl Default for Camera {
 #[derive(Component)] 
 pub struct LockedOn;
 
-fn cursor_locked(
-  windows: ResMut<Windows>
-) -> bool {
-  windows.get_primary().unwrap().cursor_locked()
-}
-
 fn camera_move(
   keys: Res<Input<KeyCode>>,
   time: Res<Time>,
@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+use bevy_flycam::MovementSettings;
+use iyes_loopless::prelude::*;
+use shared::{
+  blocks::BlockTypeManager,
+  physics::{move_player, GRAVITY, TERMINAL_VELOCITY, JUMP_VELOCITY, WALK_SPEED, FLY_SPEED, PLAYER_EYE_HEIGHT},
+  types::chunk::{ChunkDataComponent, ChunkPosition, ChunkMap},
+};
+use crate::{
+  GameState,
+  player::{MainPlayer, cursor_locked},
+};
+
+//Long frames are split into smaller steps, so fast falling players can't skip through blocks
+const MAX_STEP: f32 = 1. / 20.;
+
+//Players collide with blocks in both modes, the server moves them through the world the same way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovementMode {
+  Walking,
+  //No gravity, Space and Shift move up and down
+  Flying,
+}
+
+#[derive(Component, Debug)]
+pub struct PlayerController {
+  pub mode: MovementMode,
+  pub velocity: Vec3,
+  pub on_ground: bool,
+}
+impl Default for PlayerController {
+  fn default() -> Self {
+    Self {
+      mode: MovementMode::Walking,
+      velocity: Vec3::ZERO,
+      on_ground: false,
+    }
+  }
+}
+
+fn toggle_movement_mode(
+  keys: Res<Input<KeyCode>>,
+  windows: Res<Windows>,
+  mut players: Query<&mut PlayerController, With<MainPlayer>>,
+) {
+  if !(keys.just_pressed(KeyCode::F) && cursor_locked(windows)) {
+    return;
+  }
+  for mut controller in players.iter_mut() {
+    controller.mode = match controller.mode {
+      MovementMode::Walking => MovementMode::Flying,
+      MovementMode::Flying => MovementMode::Walking,
+    };
+    controller.velocity = Vec3::ZERO;
+    controller.on_ground = false;
+    info!("Movement mode: {:?}", controller.mode);
+  }
+}
+
+fn move_main_player(
+  time: Res<Time>,
+  keys: Res<Input<KeyCode>>,
+  windows: Res<Windows>,
+  blocks: Res<BlockTypeManager>,
+  chunk_map: Res<ChunkMap>,
+  chunks: Query<&ChunkDataComponent>,
+  mut players: Query<(&mut Transform, &mut PlayerController), With<MainPlayer>>,
+) {
+  let (mut transform, mut controller) = match players.get_single_mut() {
+    Ok(player) => player,
+    Err(_) => return
+  };
+  //Chunks that are not loaded yet are solid, so the player doesn't fall through them
+  //There's nothing above the world and nothing but solid ground below it
+  let is_solid = |(x, y, z): (i64, i64, i64)| match ChunkPosition::from_block(x, y, z) {
+    Some((position, (bx, by, bz))) => match chunk_map.get(position).and_then(|entity| chunks.get(entity).ok()) {
+      Some(data) => blocks.get_by_state(data.0.get(bx, by, bz).state).map_or(true, |meta| meta.is_solid()),
+      None => true
+    },
+    None => y < 0
+  };
+
+  //Move in the direction the camera is facing
+  let flying = controller.mode == MovementMode::Flying;
+  let mut direction = Vec3::ZERO;
+  if cursor_locked(windows) {
+    let forward = transform.forward();
+    let forward = Vec3::new(forward.x, 0., forward.z).normalize_or_zero();
+    let right = Vec3::new(-forward.z, 0., forward.x);
+    for key in keys.get_pressed() {
+      match key {
+        KeyCode::W => direction += forward,
+        KeyCode::S => direction -= forward,
+        KeyCode::A => direction -= right,
+        KeyCode::D => direction += right,
+        _ => {}
+      }
+    }
+    if flying {
+      if keys.pressed(KeyCode::Space) { direction += Vec3::Y }
+      if keys.pressed(KeyCode::LShift) { direction -= Vec3::Y }
+    } else if keys.pressed(KeyCode::Space) && controller.on_ground {
+      controller.velocity.y = JUMP_VELOCITY;
+    }
+  }
+  if flying {
+    controller.velocity = direction.normalize_or_zero() * FLY_SPEED;
+  } else {
+    let horizontal = direction.normalize_or_zero() * WALK_SPEED;
+    controller.velocity.x = horizontal.x;
+    controller.velocity.z = horizontal.z;
+  }
+
+  let mut remaining = time.delta_seconds().min(0.25);
+  while remaining > 0. {
+    let delta = remaining.min(MAX_STEP);
+    remaining -= delta;
+    if !flying {
+      controller.velocity.y = (controller.velocity.y - GRAVITY * delta).max(-TERMINAL_VELOCITY);
+    }
+    let feet = transform.translation - Vec3::Y * PLAYER_EYE_HEIGHT;
+    let result = move_player(feet, controller.velocity * delta, controller.on_ground, &is_solid);
+    //Don't touch the transform if the player is standing still, it would be sent to the server again
+    if result.movement != Vec3::ZERO {
+      transform.translation += result.movement;
+    }
+    controller.on_ground = result.on_ground;
+    if result.collisions[1] && !flying {
+      controller.velocity.y = 0.;
+    }
+  }
+}
+
+pub struct PlayerControllerPlugin;
+impl Plugin for PlayerControllerPlugin {
+  fn build(&self, app: &mut App) {
+    //bevy_flycam only handles looking around
+    app.insert_resource(MovementSettings { speed: 0., ..default() });
+    app.add_system_set(
+      ConditionSet::new()
+        .run_in_state(GameState::InGame)
+        .with_system(toggle_movement_mode)
+        .with_system(move_main_player)
+        .into()
+    );
+  }
+}
@@ -0,0 +1,269 @@
+use bevy::prelude::*;
+use bevy::{
+  input::mouse::MouseWheel,
+  render::mesh::{PrimitiveTopology, Indices},
+};
+use iyes_loopless::prelude::*;
+use shared::{
+  blocks::{BlockTypeManager, BlockShape},
+  consts::REACH_DISTANCE,
+  types::{
+    block::Block,
+    chunk::{ChunkDataComponent, ChunkPosition, ChunkMap},
+  },
+};
+use crate::{
+  GameState,
+  networking::RequestBlockChange,
+  player::{MainPlayer, cursor_locked},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RaycastHit {
+  pub position: (i64, i64, i64),
+  //Normal of the face that was hit
+  pub normal: (i64, i64, i64),
+}
+impl RaycastHit {
+  //Position of the block that would be placed against the hit face
+  pub fn adjacent(&self) -> (i64, i64, i64) {
+    (
+      self.position.0 + self.normal.0,
+      self.position.1 + self.normal.1,
+      self.position.2 + self.normal.2,
+    )
+  }
+}
+
+//Voxel traversal (Amanatides & Woo)
+//Returns the first block for which `hits` returns true
+pub fn raycast(
+  origin: Vec3,
+  direction: Vec3,
+  max_distance: f32,
+  mut hits: impl FnMut((i64, i64, i64)) -> bool
+) -> Option<RaycastHit> {
+  let direction = direction.normalize_or_zero();
+  if direction == Vec3::ZERO {
+    return None;
+  }
+  let origin = origin.to_array();
+  let direction = direction.to_array();
+  let mut position = origin.map(|x| x.floor() as i64);
+  let mut step = [0i64; 3];
+  let mut t_max = [f32::INFINITY; 3];
+  let mut t_delta = [f32::INFINITY; 3];
+  for axis in 0..3 {
+    if direction[axis] > 0. {
+      step[axis] = 1;
+      t_max[axis] = ((position[axis] + 1) as f32 - origin[axis]) / direction[axis];
+    } else if direction[axis] < 0. {
+      step[axis] = -1;
+      t_max[axis] = (origin[axis] - position[axis] as f32) / -direction[axis];
+    } else {
+      continue;
+    }
+    t_delta[axis] = 1. / direction[axis].abs();
+  }
+  let mut normal = [0i64; 3];
+  loop {
+    if hits((position[0], position[1], position[2])) {
+      return Some(RaycastHit {
+        position: (position[0], position[1], position[2]),
+        normal: (normal[0], normal[1], normal[2]),
+      });
+    }
+    let axis = match (t_max[0] < t_max[1], t_max[0] < t_max[2], t_max[1] < t_max[2]) {
+      (true, true, _) => 0,
+      (false, _, true) => 1,
+      _ => 2
+    };
+    if t_max[axis] > max_distance {
+      return None;
+    }
+    position[axis] += step[axis];
+    t_max[axis] += t_delta[axis];
+    normal = [0; 3];
+    normal[axis] = -step[axis];
+  }
+}
+
+//Block the player is currently looking at
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TargetBlock(pub Option<RaycastHit>);
+
+//Index of the block type that gets placed
+#[derive(Clone, Copy, Debug)]
+pub struct SelectedBlock(pub usize);
+impl Default for SelectedBlock {
+  //Skip air
+  fn default() -> Self { Self(1) }
+}
+
+#[derive(Component)]
+pub struct BlockHighlight;
+
+fn highlight_mesh() -> Mesh {
+  //Slightly larger than the block to prevent z-fighting
+  const MIN: f32 = -0.005;
+  const MAX: f32 = 1.005;
+  //Bits 1, 2 and 4 of the corner index select the max X, Y and Z side
+  let vertices: Vec<[f32; 3]> = (0..8).map(|corner| [
+    if corner & 1 != 0 { MAX } else { MIN },
+    if corner & 2 != 0 { MAX } else { MIN },
+    if corner & 4 != 0 { MAX } else { MIN },
+  ]).collect();
+  let indices = vec![
+    0, 1, 1, 3, 3, 2, 2, 0, //Z min face
+    4, 5, 5, 7, 7, 6, 6, 4, //Z max face
+    0, 4, 1, 5, 2, 6, 3, 7, //Edges along Z
+  ];
+  let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+  mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 1., 0.]; vertices.len()]);
+  mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0., 0.]; vertices.len()]);
+  mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
+  mesh.set_indices(Some(Indices::U32(indices)));
+  mesh
+}
+
+fn setup_highlight(
+  mut commands: Commands,
+  mut meshes: ResMut<Assets<Mesh>>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  commands.spawn_bundle(PbrBundle {
+    mesh: meshes.add(highlight_mesh()),
+    material: materials.add(StandardMaterial {
+      base_color: Color::BLACK,
+      unlit: true,
+      ..default()
+    }),
+    visibility: Visibility { is_visible: false },
+    ..default()
+  }).insert(BlockHighlight);
+  commands.insert_resource(TargetBlock::default());
+}
+
+fn cleanup_highlight(
+  mut commands: Commands,
+  highlight: Query<Entity, With<BlockHighlight>>,
+) {
+  for entity in highlight.iter() {
+    commands.entity(entity).despawn();
+  }
+  commands.remove_resource::<TargetBlock>();
+}
+
+fn update_target_block(
+  mut target: ResMut<TargetBlock>,
+  blocks: Res<BlockTypeManager>,
+  player: Query<&GlobalTransform, With<MainPlayer>>,
+  chunk_map: Res<ChunkMap>,
+  chunks: Query<&ChunkDataComponent>,
+  mut highlight: Query<(&mut Transform, &mut Visibility), With<BlockHighlight>>,
+) {
+  let camera = match player.get_single() {
+    Ok(camera) => camera,
+    Err(_) => return
+  };
+
+  target.0 = raycast(camera.translation, camera.forward(), REACH_DISTANCE, |(x, y, z)| {
+    let (position, (bx, by, bz)) = match ChunkPosition::from_block(x, y, z) {
+      Some(located) => located,
+      None => return false
+    };
+    let block: Block = match chunk_map.get(position).and_then(|entity| chunks.get(entity).ok()) {
+      Some(data) => data.0.get(bx, by, bz),
+      None => return false
+    };
+    blocks.get_by_state(block.state).map_or(false, |meta| {
+      !(meta.is_air() || meta.is_liquid() || meta.shape == BlockShape::None)
+    })
+  });
+
+  for (mut transform, mut visibility) in highlight.iter_mut() {
+    visibility.is_visible = target.0.is_some();
+    if let Some(hit) = target.0 {
+      transform.translation = Vec3::new(hit.position.0 as f32, hit.position.1 as f32, hit.position.2 as f32);
+    }
+  }
+}
+
+fn select_block(
+  keys: Res<Input<KeyCode>>,
+  mut wheel: EventReader<MouseWheel>,
+  mut selected: ResMut<SelectedBlock>,
+  blocks: Res<BlockTypeManager>,
+) {
+  //Air (index 0) can't be selected
+  let count = blocks.amount();
+  if count < 2 { return }
+  let mut index = selected.0;
+  const DIGITS: [KeyCode; 9] = [
+    KeyCode::Key1, KeyCode::Key2, KeyCode::Key3,
+    KeyCode::Key4, KeyCode::Key5, KeyCode::Key6,
+    KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+  ];
+  for (digit, key) in DIGITS.iter().enumerate() {
+    if keys.just_pressed(*key) && digit + 1 < count {
+      index = digit + 1;
+    }
+  }
+  for event in wheel.iter() {
+    let offset = if event.y > 0. { count - 2 } else if event.y < 0. { 1 } else { 0 };
+    index = (index - 1 + offset) % (count - 1) + 1;
+  }
+  if index != selected.0 {
+    selected.0 = index;
+    if let Some(block) = blocks.get_by_index(index) {
+      info!("Selected block: {}", block.name);
+    }
+  }
+}
+
+fn block_interaction_input(
+  mouse: Res<Input<MouseButton>>,
+  target: Res<TargetBlock>,
+  selected: Res<SelectedBlock>,
+  blocks: Res<BlockTypeManager>,
+  mut requests: EventWriter<RequestBlockChange>,
+) {
+  let hit = match target.0 {
+    Some(hit) => hit,
+    None => return
+  };
+  //The world is only updated after the server confirms the change
+  if mouse.just_pressed(MouseButton::Left) {
+    requests.send(RequestBlockChange::Break { position: hit.position });
+  } else if mouse.just_pressed(MouseButton::Right) {
+    if let Some(block) = blocks.get_by_index(selected.0) {
+      requests.send(RequestBlockChange::Place {
+        position: hit.adjacent(),
+        block: Block { state: block.default_state() }
+      });
+    }
+  }
+}
+
+pub struct BlockInteractionPlugin;
+impl Plugin for BlockInteractionPlugin {
+  fn build(&self, app: &mut App) {
+    app.init_resource::<SelectedBlock>();
+    app.add_enter_system(GameState::InGame, setup_highlight);
+    app.add_exit_system(GameState::InGame, cleanup_highlight);
+    app.add_system_set(
+      ConditionSet::new()
+        .run_in_state(GameState::InGame)
+        .with_system(update_target_block)
+        .into()
+    );
+    app.add_system_set(
+      ConditionSet::new()
+        .run_in_state(GameState::InGame)
+        .run_if(cursor_locked)
+        .with_system(select_block)
+        .with_system(block_interaction_input)
+        .into()
+    );
+  }
+}
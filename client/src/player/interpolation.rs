@@ -0,0 +1,165 @@
+use bevy::prelude::*;
+use iyes_loopless::prelude::*;
+use std::collections::VecDeque;
+use shared::{
+  consts::SERVER_TICK_RATE,
+  types::{net::Lobby, player::Orientation},
+};
+use crate::{
+  GameState,
+  player::NetPlayer,
+};
+
+//Remote players are rendered this many seconds in the past,
+//so there's (almost) always a newer snapshot to interpolate towards, even if some packets are lost
+const INTERPOLATION_DELAY: f64 = 0.1;
+//How quickly the server clock estimate follows new packets
+const CLOCK_SMOOTHING: f64 = 0.1;
+//The server clock estimate is reset instead of smoothed if it's off by more than this many seconds
+const CLOCK_RESET_THRESHOLD: f64 = 1.;
+const MAX_SNAPSHOTS: usize = 32;
+
+//Sent by handle_incoming_stuff for every PlayerSync message
+#[derive(Clone, Copy, Debug)]
+pub struct NetPlayerSnapshot {
+  pub client_id: u64,
+  pub position: Vec3,
+  pub orientation: Orientation,
+  pub tick: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Snapshot {
+  //Server time in seconds
+  time: f64,
+  position: Vec3,
+  orientation: Orientation,
+}
+
+//Estimated difference between the server clock and the local one
+#[derive(Default, Debug)]
+pub struct ServerClock(Option<f64>);
+impl ServerClock {
+  fn update(&mut self, server_time: f64, now: f64) {
+    let offset = server_time - now;
+    self.0 = Some(match self.0 {
+      Some(current) if (offset - current).abs() < CLOCK_RESET_THRESHOLD => {
+        current + (offset - current) * CLOCK_SMOOTHING
+      },
+      _ => offset
+    });
+  }
+
+  //Server time remote players should be rendered at
+  fn render_time(&self, now: f64) -> Option<f64> {
+    Some(now + self.0? - INTERPOLATION_DELAY)
+  }
+}
+
+//Recent states of a remote player, sorted by time
+#[derive(Component, Debug)]
+pub struct InterpolationBuffer(VecDeque<Snapshot>);
+impl InterpolationBuffer {
+  pub fn new(position: Vec3, orientation: Orientation) -> Self {
+    Self(VecDeque::from([Snapshot { time: 0., position, orientation }]))
+  }
+
+  fn push(&mut self, snapshot: Snapshot, render_time: f64) {
+    //If the player stood still for a while, it should only start moving once the new snapshot is due,
+    //instead of sliding all the way from the last time it moved
+    if let Some(last) = self.0.back_mut() {
+      if last.time < render_time && snapshot.time > render_time {
+        last.time = render_time;
+      }
+    }
+    //Unreliable messages can arrive out of order or more than once
+    match self.0.iter().rposition(|other| other.time <= snapshot.time) {
+      Some(index) if self.0[index].time == snapshot.time => self.0[index] = snapshot,
+      Some(index) => self.0.insert(index + 1, snapshot),
+      None => self.0.push_front(snapshot),
+    }
+    while self.0.len() > MAX_SNAPSHOTS {
+      self.0.pop_front();
+    }
+  }
+
+  fn sample(&mut self, render_time: f64) -> Option<(Vec3, Orientation)> {
+    //Only the last snapshot before the render time is still needed
+    while self.0.len() > 1 && self.0[1].time <= render_time {
+      self.0.pop_front();
+    }
+    let from = self.0.front()?;
+    match self.0.get(1) {
+      Some(to) if from.time <= render_time => {
+        let t = ((render_time - from.time) / (to.time - from.time)) as f32;
+        Some((from.position.lerp(to.position, t), from.orientation.lerp(to.orientation, t)))
+      },
+      _ => Some((from.position, from.orientation))
+    }
+  }
+}
+
+fn interpolate_net_players(
+  time: Res<Time>,
+  lobby: Option<Res<Lobby>>,
+  mut clock: ResMut<ServerClock>,
+  mut snapshots: EventReader<NetPlayerSnapshot>,
+  mut players: Query<(&mut InterpolationBuffer, &mut Transform), With<NetPlayer>>,
+) {
+  let now = time.seconds_since_startup();
+  if let Some(lobby) = lobby {
+    for event in snapshots.iter() {
+      let server_time = event.tick as f64 / SERVER_TICK_RATE as f64;
+      clock.update(server_time, now);
+      let render_time = match clock.render_time(now) {
+        Some(render_time) => render_time,
+        None => continue
+      };
+      let entity = match lobby.players.get(&event.client_id) {
+        Some(&entity) => entity,
+        None => continue
+      };
+      let mut buffer = match players.get_mut(entity) {
+        Ok((buffer, _)) => buffer,
+        Err(_) => continue
+      };
+      buffer.push(Snapshot {
+        time: server_time,
+        position: event.position,
+        orientation: event.orientation,
+      }, render_time);
+    }
+  }
+
+  let render_time = match clock.render_time(now) {
+    Some(render_time) => render_time,
+    None => return
+  };
+  for (mut buffer, mut transform) in players.iter_mut() {
+    if let Some((position, orientation)) = buffer.sample(render_time) {
+      transform.translation = position;
+      //There's no separate head model yet, so the body only turns around the Y axis
+      transform.rotation = Quat::from_axis_angle(Vec3::Y, orientation.yaw);
+    }
+  }
+}
+
+fn reset_clock(mut clock: ResMut<ServerClock>) {
+  *clock = ServerClock::default();
+}
+
+pub struct InterpolationPlugin;
+impl Plugin for InterpolationPlugin {
+  fn build(&self, app: &mut App) {
+    app.add_event::<NetPlayerSnapshot>();
+    app.init_resource::<ServerClock>();
+    app.add_system_set(
+      ConditionSet::new()
+        .run_in_state(GameState::InGame)
+        .after("NetLoop")
+        .with_system(interpolate_net_players)
+        .into()
+    );
+    app.add_exit_system(GameState::InGame, reset_clock);
+  }
+}
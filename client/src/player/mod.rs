@@ -5,11 +5,24 @@ use shared::consts::CHUNK_SIZE;
 use crate::GameState;
 
 mod camera;
+mod interaction;
+mod controller;
+mod interpolation;
+use interaction::BlockInteractionPlugin;
+use controller::PlayerControllerPlugin;
+use interpolation::InterpolationPlugin;
+pub use controller::PlayerController;
+pub use interpolation::{InterpolationBuffer, NetPlayerSnapshot};
 //use camera::{CameraPlugin, Camera as PlayerCam};
 
 #[derive(Component, Default)]
 pub struct MainPlayer;
 
+//Player input is only handled while the cursor is grabbed by the game
+pub fn cursor_locked(windows: Res<Windows>) -> bool {
+  windows.get_primary().map_or(false, |window| window.cursor_locked())
+}
+
 #[derive(Component, Default)]
 pub struct NetPlayer;
 
@@ -45,6 +58,7 @@ fn setup(
     .insert(FlyCam)
     .insert(Player)
     .insert(MainPlayer)
+    .insert(PlayerController::default())
     .insert(ChunkLocation::default());
 }
 fn on_exit (
@@ -60,6 +74,9 @@ pub struct PlayerPlugin;
 impl Plugin for PlayerPlugin {
   fn build(&self, app: &mut App) {
     app.add_plugin(FlyCamPlugin);
+    app.add_plugin(BlockInteractionPlugin);
+    app.add_plugin(PlayerControllerPlugin);
+    app.add_plugin(InterpolationPlugin);
     app.add_system(update_chunk_location);
     app.add_enter_system(GameState::InGame, setup);
     app.add_exit_system(GameState::InGame, on_exit);
@@ -3,28 +3,28 @@ use iyes_loopless::prelude::*;
 
 use bevy::{
   tasks::{Task, AsyncComputeTaskPool},
-  utils::HashSet,
+  utils::HashMap,
 };
 use shared::blocks::BlockShape;
 use crate::{
   GameState,
-  networking::RequestChunk,
-  player::{ChunkLocation, MainPlayer},
+  networking::{BlockUpdateEvt, ChunkUnloadEvt, DecompressTask},
   assets::{AssetLoaderState, BlockTextureAtlas, BlockTextureIndexMap},
   
-  mesh_builder::MeshBuilder
+  mesh_builder::{MeshBuilder, GreedyFaces, FaceSink, AtlasTile, FaceAo, vertex_ao, vertex_ao_neighbours},
+  chunk_material::ChunkMaterial,
+  light::{LightMap, ChunkLight, LightLevel},
 };
 use shared::{
   types::{
     CubeFace,
     block::Block, 
-    chunk::{ChunkDataComponent, ChunkPosition, Chunk},
+    chunk::{ChunkData, ChunkDataComponent, ChunkPosition, ChunkMap, Chunk},
   },
-  consts::{CHUNK_HEIGHT, CHUNK_SIZE, DEFAULT_CLIENT_VIEW_DIST},
-  blocks::{BlockTypeManager, BlockMetadata}
+  consts::{CHUNK_HEIGHT, CHUNK_SIZE, SECTION_HEIGHT, CHUNK_SECTIONS},
+  blocks::BlockTypeManager
 };
 use futures_lite::future;
-use std::ops::RangeInclusive;
 
 const MAX_STARTED_MESH_BUILD_TASKS_PER_TICK: usize = 10;
 const MAX_PROCESSED_FINISHED_BUILD_TASKS_PER_TICK: usize = usize::MAX;
@@ -36,143 +36,439 @@ pub enum MeshStage {
   Ready
 }
 
+#[derive(Debug, Default)]
+pub struct ChunkMeshes {
+  pub opaque: Option<Mesh>,
+  pub transparent: Option<Mesh>,
+}
+
 #[derive(Component, Debug)]
-pub struct MeshTask(Task<Mesh>);
+pub struct MeshTask(Task<Vec<(usize, ChunkMeshes)>>);
+
+//Child of the chunk entity that holds the mesh of a single section
+#[derive(Component, Debug, Clone, Copy)]
+pub struct SectionMesh {
+  pub section: usize,
+}
 
-fn chunk_distance(pos: &ChunkPosition, loc: &ChunkLocation) -> usize {
-  ((pos.0 - loc.0).abs()).max((pos.1 - loc.1).abs()) as _
+//Bitmask of sections that need to be (re)meshed
+#[derive(Component, Debug, Clone, Copy, Default)]
+pub struct DirtySections(pub u16);
+impl DirtySections {
+  pub const ALL: Self = Self(u16::MAX);
+  #[inline] pub fn mark(&mut self, section: usize) {
+    self.0 |= 1 << section;
+  }
+  #[inline] pub fn contains(&self, section: usize) -> bool {
+    (self.0 & (1 << section)) != 0
+  }
+  #[inline] pub fn is_empty(&self) -> bool {
+    self.0 == 0
+  }
 }
+const _: () = assert!(CHUNK_SECTIONS <= u16::BITS as usize);
 
-fn update_loaded_chunks_around_player (
+//Calls `mark` for every section whose mesh depends on the block at `block` in chunk `position`:
+//its own section, adjacent sections if the block is on a section border
+//and the same section of adjacent chunks if the block is on a chunk border
+pub(crate) fn for_each_affected_section(
+  position: ChunkPosition,
+  (bx, by, bz): (usize, usize, usize),
+  mut mark: impl FnMut(ChunkPosition, usize)
+) {
+  let section = by / SECTION_HEIGHT;
+  mark(position, section);
+  if by % SECTION_HEIGHT == 0 && section > 0 {
+    mark(position, section - 1);
+  }
+  if by % SECTION_HEIGHT == SECTION_HEIGHT - 1 && section < CHUNK_SECTIONS - 1 {
+    mark(position, section + 1);
+  }
+  if bx == 0 { mark(ChunkPosition(position.0 - 1, position.1), section); }
+  if bx == CHUNK_SIZE - 1 { mark(ChunkPosition(position.0 + 1, position.1), section); }
+  if bz == 0 { mark(ChunkPosition(position.0, position.1 - 1), section); }
+  if bz == CHUNK_SIZE - 1 { mark(ChunkPosition(position.0, position.1 + 1), section); }
+}
+
+fn unload_chunks(
   mut commands: Commands,
-  mut ev_reqest: EventWriter<RequestChunk>,
-  chunks: Query<(Entity, &ChunkPosition), With<Chunk>>,
-  player_chunk: Query<&ChunkLocation, (With<MainPlayer>, Changed<ChunkLocation>)>
+  mut events: EventReader<ChunkUnloadEvt>,
+  mut chunk_map: ResMut<ChunkMap>,
+  mut light_map: ResMut<LightMap>,
+  mut dirty: Query<&mut DirtySections>,
 ) {
-  if player_chunk.is_empty() { 
-    return;
+  let mut unloaded = Vec::new();
+  for ChunkUnloadEvt(position) in events.iter() {
+    if let Some(entity) = chunk_map.get(*position) {
+      commands.entity(entity).despawn_recursive();
+      chunk_map.remove(*position);
+      light_map.remove(*position);
+      unloaded.push(*position);
+      info!("Unloaded {:?}", position);
+    }
+  }
+
+  //Chunks next to unloaded ones need their border faces back
+  for position in unloaded {
+    for neighbour in adjacent_chunks(position) {
+      if let Some(entity) = chunk_map.get(neighbour) {
+        if let Ok(mut dirty) = dirty.get_mut(entity) {
+          *dirty = DirtySections::ALL;
+        }
+      }
+    }
   }
-  let player_chunk = player_chunk.single();
-
-  info!("Player moved to chunk {:?}", &player_chunk);
-
-  //Unload chunks and build HashSet of chunks that are still loaded
-  let mut loaded = HashSet::new();
-  for (entity, chunk_pos) in chunks.iter() {
-    if chunk_distance(chunk_pos, player_chunk) <= DEFAULT_CLIENT_VIEW_DIST {
-      loaded.insert(*chunk_pos);
-    } else {
-      commands.entity(entity).despawn();
-      info!("Unloaded {:?}", chunk_pos);
+}
+
+//Data needed to build chunk meshes outside of the main thread
+#[derive(Clone)]
+struct MeshContext {
+  block_types: BlockTypeManager,
+  textures: Vec<bevy::sprite::Rect>,
+  atlas_size: Vec2,
+  tex_map: HashMap<String, usize>,
+}
+
+//Chunks that share a face with the chunk at `position`
+pub fn adjacent_chunks(position: ChunkPosition) -> [ChunkPosition; 4] {
+  [
+    ChunkPosition(position.0 - 1, position.1),
+    ChunkPosition(position.0 + 1, position.1),
+    ChunkPosition(position.0, position.1 - 1),
+    ChunkPosition(position.0, position.1 + 1),
+  ]
+}
+
+//The chunk being meshed along with the 3x3 area of chunks around it
+//Cloning ChunkData and ChunkLight is cheap, so every mesh task gets its own copy
+#[derive(Clone)]
+struct ChunkNeighbourhood {
+  //Index: (z + 1) * 3 + (x + 1)
+  chunks: [Option<ChunkData>; 9],
+  lights: [Option<ChunkLight>; 9],
+}
+impl ChunkNeighbourhood {
+  #[inline] fn center(&self) -> &ChunkData {
+    self.chunks[4].as_ref().expect("Center chunk is missing")
+  }
+
+  //Coordinates are relative to the center chunk
+  //Returns None if the block is outside of the world or in a chunk that is not loaded
+  fn get(&self, x: isize, y: isize, z: isize) -> Option<Block> {
+    const SIZE: isize = CHUNK_SIZE as isize;
+    if !(0..CHUNK_HEIGHT as isize).contains(&y) {
+      return None;
+    }
+    let (cx, cz) = (x.div_euclid(SIZE), z.div_euclid(SIZE));
+    if cx.abs() > 1 || cz.abs() > 1 {
+      return None;
     }
+    let chunk = self.chunks[((cz + 1) * 3 + cx + 1) as usize].as_ref()?;
+    Some(chunk.get(x.rem_euclid(SIZE) as usize, y as usize, z.rem_euclid(SIZE) as usize))
   }
 
-  //Load new chunks
-  const RANGE: RangeInclusive<i64> = (-(DEFAULT_CLIENT_VIEW_DIST as i64))..=(DEFAULT_CLIENT_VIEW_DIST as i64);
-  for x in RANGE {
-    for y in RANGE {
-      let position = ChunkPosition(x + player_chunk.0, y + player_chunk.1);
-      if !loaded.contains(&position) {
-        info!("Requesting chunk {:?}", &position);
-        ev_reqest.send(position.into());
+  //Coordinates are relative to the center chunk
+  //Blocks above the world and in chunks that are not lit yet get full sky light
+  fn light(&self, x: isize, y: isize, z: isize) -> LightLevel {
+    const SIZE: isize = CHUNK_SIZE as isize;
+    if y < 0 {
+      return LightLevel::default();
+    }
+    if y >= CHUNK_HEIGHT as isize {
+      return LightLevel::SKY;
+    }
+    let (cx, cz) = (x.div_euclid(SIZE), z.div_euclid(SIZE));
+    if cx.abs() > 1 || cz.abs() > 1 {
+      return LightLevel::SKY;
+    }
+    match &self.lights[((cz + 1) * 3 + cx + 1) as usize] {
+      Some(light) => light.get(x.rem_euclid(SIZE) as usize, y as usize, z.rem_euclid(SIZE) as usize),
+      None => LightLevel::SKY
+    }
+  }
+}
+
+//How cube faces of a chunk are turned into quads
+//Greedy by default, F4 switches between the modes to compare them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshingMode {
+  //One quad per visible face
+  Simple,
+  //Coplanar faces with the same texture are merged into larger quads
+  Greedy,
+}
+impl Default for MeshingMode {
+  fn default() -> Self { Self::Greedy }
+}
+
+fn build_section_mesh(section: usize, chunks: &ChunkNeighbourhood, ctx: &MeshContext, mode: MeshingMode) -> ChunkMeshes {
+  let blocks = chunks.center();
+  let MeshContext { block_types, textures, atlas_size, tex_map } = ctx;
+  let mut opaque_builder = MeshBuilder::default();
+  let mut transparent_builder = MeshBuilder::default();
+  let new_greedy = || (mode == MeshingMode::Greedy).then(|| GreedyFaces::new([CHUNK_SIZE, SECTION_HEIGHT, CHUNK_SIZE]));
+  let mut opaque_greedy = new_greedy();
+  let mut transparent_greedy = new_greedy();
+  let y_offset = section * SECTION_HEIGHT;
+  for x in 0..CHUNK_SIZE {
+    for y in y_offset..(y_offset + SECTION_HEIGHT) {
+      for z in 0..CHUNK_SIZE {
+        //=========================
+
+        let block: Block = blocks.get(x, y, z);
+        let block_meta = block_types.get_by_state(block.state).expect("Invalid block state");
+        if block_meta.is_air() || block_meta.shape == BlockShape::None { continue; }
+
+        //Undo the block rotation to get the face texture
+        let rotation = (4 - block_meta.facing(block.state).rotation()) % 4;
+
+        //Transparent blocks go into a separate mesh
+        let (builder, greedy) = match block_meta.is_transparent() {
+          true => (&mut transparent_builder, &mut transparent_greedy),
+          false => (&mut opaque_builder, &mut opaque_greedy),
+        };
+
+        //=========================
+
+        //Coordinates are relative to the section
+        let coord = [x as u8, (y - y_offset) as u8, z as u8];
+
+        //Returns true if the face pointing towards the neighbour at (dx, dy, dz) is visible
+        let query = |face: CubeFace, dx: i8, dy: i8, dz: i8| -> bool {
+          //Blocks in neighbouring chunks are checked too, unloaded ones count as air
+          let neighbour = match chunks.get(
+            x as isize + dx as isize, 
+            y as isize + dy as isize, 
+            z as isize + dz as isize 
+          ) {
+            Some(neighbour) => neighbour,
+            None => return true
+          };
+          let meta = block_types.get_by_state(neighbour.state).expect("Invalid block state");
+          if meta.is_air() || meta.shape != BlockShape::Cube {
+            return true;
+          }
+          if meta.is_opaque() {
+            return false;
+          }
+          //Faces between transparent blocks of the same type can be culled
+          !(meta.index == block_meta.index && block_meta.optimize_sides[face as usize])
+        };
+
+        //Returns true if the neighbour at the offset darkens nearby vertices
+        let occludes = |[dx, dy, dz]: [i8; 3]| -> bool {
+          chunks.get(
+            x as isize + dx as isize,
+            y as isize + dy as isize,
+            z as isize + dz as isize
+          ).map_or(false, |neighbour| {
+            block_types.get_by_state(neighbour.state).expect("Invalid block state").is_opaque()
+          })
+        };
+        //Faces are lit by the block they're facing
+        let face_light = |face: CubeFace| -> u8 {
+          let (dx, dy, dz) = face.offset();
+          chunks.light(
+            x as isize + dx as isize,
+            y as isize + dy as isize,
+            z as isize + dz as isize
+          ).max()
+        };
+        let face_ao = |face: CubeFace| -> FaceAo {
+          vertex_ao_neighbours(face).map(|[side_u, side_v, corner]| {
+            vertex_ao(occludes(side_u), occludes(side_v), occludes(corner))
+          })
+        };
+        /*const UV: [[f32; 2]; 4] = [
+          [1.0, 1.0],
+          [1.0, 0.0],
+          [0.0, 1.0],
+          [0.0, 0.0],
+        ];*/
+
+        let face_tile = |face: CubeFace| -> AtlasTile {
+          //what
+          //the
+          //fuck
+          let tex_index = block_meta.face_textures[face.rotate_y(rotation) as usize];
+          let tex_path = block_meta.textures[tex_index].partial();
+          let atlas_tex_idx = *tex_map.get(tex_path).expect("No texture");
+          let min = textures[atlas_tex_idx].min / *atlas_size;
+          let max = textures[atlas_tex_idx].max / *atlas_size;
+          [min.x, min.y, max.x - min.x, max.y - min.y]
+        };
+        
+        match &block_meta.shape {
+          BlockShape::Cube => {
+            let faces: &mut dyn FaceSink = match greedy {
+              Some(greedy) => greedy,
+              None => builder
+            };
+            for face in CubeFace::ALL {
+              let (dx, dy, dz) = face.offset();
+              if query(face, dx, dy, dz) {
+                faces.add_face(face, coord, face_tile(face), face_ao(face), face_light(face));
+              }
+            }
+          },
+          BlockShape::Cross => {
+            //Cross-shaped blocks are never culled or merged
+            let light = chunks.light(x as isize, y as isize, z as isize).max();
+            builder.add_cross(coord, face_tile(CubeFace::Front), light);
+          },
+          _ => {
+            error!("UNIMPLEMENTED SHAPE");
+            panic!("UNIMPLEMENTED SHAPE");
+          }
+        }
+        //=========================
       }
     }
   }
+  if let Some(greedy) = opaque_greedy {
+    greedy.build_into(&mut opaque_builder);
+  }
+  if let Some(greedy) = transparent_greedy {
+    greedy.build_into(&mut transparent_builder);
+  }
+  ChunkMeshes {
+    opaque: (!opaque_builder.is_empty()).then(|| opaque_builder.build()),
+    transparent: (!transparent_builder.is_empty()).then(|| transparent_builder.build()),
+  }
+}
+
+fn apply_block_updates(
+  mut events: EventReader<BlockUpdateEvt>,
+  chunk_map: Res<ChunkMap>,
+  mut chunks: Query<(Option<&mut ChunkDataComponent>, Option<&mut DecompressTask>, &mut DirtySections)>,
+) {
+  for event in events.iter() {
+    let (x, y, z) = event.position;
+    let (position, (bx, by, bz)) = match ChunkPosition::from_block(x, y, z) {
+      Some(located) => located,
+      None => continue
+    };
+    //Updates for chunks that are not loaded are ignored
+    let entity = match chunk_map.get(position) {
+      Some(entity) => entity,
+      None => continue
+    };
+    {
+      let (data, task, _) = match chunks.get_mut(entity) {
+        Ok(chunk) => chunk,
+        Err(_) => continue
+      };
+      //New data is on its way, apply the update on top of it too
+      if let Some(mut task) = task {
+        task.queued.push(((bx, by, bz), event.block));
+      }
+      let mut data = match data {
+        Some(data) => data,
+        None => continue
+      };
+      data.0.set(bx, by, bz, event.block);
+    }
+
+    //Faces of blocks in neighbouring sections and chunks may be affected too
+    for_each_affected_section(position, (bx, by, bz), |chunk, section| {
+      if let Some(entity) = chunk_map.get(chunk) {
+        if let Ok((_, _, mut dirty)) = chunks.get_mut(entity) {
+          dirty.mark(section);
+        }
+      }
+    });
+  }
+}
+
+//Border faces of adjacent chunks were built without this chunk's data
+fn mark_neighbours_of_new_chunks(
+  chunk_map: Res<ChunkMap>,
+  new_chunks: Query<&ChunkPosition, Added<ChunkDataComponent>>,
+  mut dirty: Query<&mut DirtySections, With<ChunkDataComponent>>,
+) {
+  for position in new_chunks.iter() {
+    for neighbour in adjacent_chunks(*position) {
+      if let Some(entity) = chunk_map.get(neighbour) {
+        if let Ok(mut dirty) = dirty.get_mut(entity) {
+          *dirty = DirtySections::ALL;
+        }
+      }
+    }
+  }
+}
+
+fn toggle_meshing_mode(
+  keys: Res<Input<KeyCode>>,
+  mut mode: ResMut<MeshingMode>,
+  mut chunks: Query<&mut DirtySections>,
+) {
+  if !keys.just_pressed(KeyCode::F4) {
+    return;
+  }
+  *mode = match *mode {
+    MeshingMode::Simple => MeshingMode::Greedy,
+    MeshingMode::Greedy => MeshingMode::Simple,
+  };
+  //Every loaded chunk is rebuilt with the new mode
+  for mut dirty in chunks.iter_mut() {
+    *dirty = DirtySections::ALL;
+  }
+  info!("Meshing mode: {:?}", *mode);
 }
 
 fn mesh_gen_system(
   mut commands: Commands,
-  chunks: Query<(Entity, &ChunkDataComponent, &ChunkPosition), Without<MeshStage>>,
+  mut chunks: Query<(Entity, &ChunkDataComponent, &ChunkPosition, &mut DirtySections), Without<MeshTask>>,
+  chunk_map: Res<ChunkMap>,
+  light_map: Res<LightMap>,
+  chunk_data: Query<&ChunkDataComponent>,
   pool: Res<AsyncComputeTaskPool>,
   ref atlas: Res<BlockTextureAtlas>,
   block_types: Res<BlockTypeManager>,
-  index_map: Res<BlockTextureIndexMap>
+  index_map: Res<BlockTextureIndexMap>,
+  mode: Res<MeshingMode>,
 ) {
-  for (entity, chunk, position) in chunks.iter().take(MAX_STARTED_MESH_BUILD_TASKS_PER_TICK) {
+  let mode = *mode;
+  let mut shared_ctx: Option<MeshContext> = None;
+  let dirty_chunks = chunks.iter_mut()
+    //Chunks can't be meshed before their light is known
+    .filter(|(_, _, position, dirty)| !dirty.is_empty() && light_map.get(**position).is_some())
+    .take(MAX_STARTED_MESH_BUILD_TASKS_PER_TICK);
+  for (entity, chunk, position, mut dirty) in dirty_chunks {
     info!("Starting mesh build task for chunk: \"{:?}\"...", position);
 
-    let blocks = chunk.0.0.clone();
-    let textures = atlas.get().textures.clone();
-    let atlas_size = atlas.get().size;
-    let metadatas: Vec<BlockMetadata> = block_types.block_types.clone();
-    let tex_map = index_map.0.clone();
+    let ctx = shared_ctx.get_or_insert_with(|| MeshContext {
+      block_types: block_types.clone(),
+      textures: atlas.get().textures.clone(),
+      atlas_size: atlas.get().size,
+      tex_map: index_map.0.clone(),
+    }).clone();
+    let mut neighbourhood = ChunkNeighbourhood { chunks: Default::default(), lights: Default::default() };
+    for dz in -1..=1 {
+      for dx in -1..=1 {
+        let index = ((dz + 1) * 3 + dx + 1) as usize;
+        let neighbour = ChunkPosition(position.0 + dx, position.1 + dz);
+        neighbourhood.chunks[index] = match (dx, dz) {
+          (0, 0) => Some(chunk.0.clone()),
+          _ => chunk_map.get(neighbour)
+            .and_then(|entity| chunk_data.get(entity).ok())
+            .map(|data| data.0.clone())
+        };
+        neighbourhood.lights[index] = light_map.get(neighbour).cloned();
+      }
+    }
+    //Sections marked while the task is running are picked up once it's done
+    let sections = std::mem::take(&mut *dirty);
 
     let task = pool.spawn(async move {
-      let mut builder = MeshBuilder::default();
-      for x in 0..CHUNK_SIZE {
-        for y in 0..CHUNK_HEIGHT {
-          for z in 0..CHUNK_SIZE {
-            //=========================
-
-            let check_block = |x: &Block| {
-              let meta = &metadatas[x.block_type as usize];
-              meta.is_air() || meta.shape != BlockShape::Cube
-            };
-
-            let block: Block = blocks[x][y][z];
-            if check_block(&block) { continue; }
-
-            //=========================
-
-            let coord = [x as u8, y as u8, z as u8];
-            let query = |dx: i8, dy: i8, dz: i8| -> bool {
-              let (qx, qy, qz) = (
-                x as isize + dx as isize, 
-                y as isize + dy as isize, 
-                z as isize + dz as isize 
-              );
-              const MAX_H: isize = (CHUNK_SIZE - 1) as isize;
-              const MAX_V: isize = (CHUNK_HEIGHT - 1) as isize;
-              if qx < 0 || qy < 0 || qz < 0 || qx > MAX_H || qy > MAX_V || qz > MAX_H {
-                true
-              } else {
-                check_block(&blocks[qx as usize][qy as usize][qz as usize])
-              }
-            };
-            /*const UV: [[f32; 2]; 4] = [
-              [1.0, 1.0],
-              [1.0, 0.0],
-              [0.0, 1.0],
-              [0.0, 0.0],
-            ];*/
-
-            let face_uv = |face: CubeFace| {
-              //what
-              //the
-              //fuck
-              let meta = &metadatas[block.block_type as usize];
-              let tex_index = meta.face_textures[face as usize];
-              let tex_path = meta.textures[tex_index].partial();
-              let atlas_tex_idx = *tex_map.get(tex_path).expect("No texture");
-              let min = textures[atlas_tex_idx].min / atlas_size;
-              let max = textures[atlas_tex_idx].max / atlas_size;
-              [
-                [max.x, max.y],
-                [max.x, min.y],
-                [min.x, max.y],
-                [min.x, min.y],
-              ]
-            };
-            
-            match &metadatas[block.block_type as usize].shape {
-              BlockShape::Cube => {
-                builder.add_face_if(query(0, 1,0), CubeFace::Top,    coord, face_uv(CubeFace::Top));
-                builder.add_face_if(query(0,0,-1), CubeFace::Front,  coord, face_uv(CubeFace::Front));
-                builder.add_face_if(query(-1,0,0), CubeFace::Left,   coord, face_uv(CubeFace::Left));
-                builder.add_face_if(query(1, 0,0), CubeFace::Right,  coord, face_uv(CubeFace::Right));
-                builder.add_face_if(query(0, 0,1), CubeFace::Back,   coord, face_uv(CubeFace::Back));
-                builder.add_face_if(query(0,-1,0), CubeFace::Bottom, coord, face_uv(CubeFace::Bottom));
-              },
-              _ => {
-                error!("UNIMPLEMENTED SHAPE");
-                panic!("UNIMPLEMENTED SHAPE");
-              }
-            }
-            //=========================
-          }
-        }
-      }
-      builder.build()
+      (0..CHUNK_SECTIONS)
+        .filter(|&section| sections.contains(section))
+        .map(|section| match neighbourhood.center().section(section) {
+          //Empty sections don't need to be meshed
+          None => (section, ChunkMeshes::default()),
+          Some(_) => (section, build_section_mesh(section, &neighbourhood, &ctx, mode)),
+        })
+        .collect()
     });
     commands.entity(entity)
       .insert(MeshStage::Queued)
@@ -182,35 +478,73 @@ fn mesh_gen_system(
 
 fn apply_mesh_gen_tasks(
   mut commands: Commands,
-  mut query: Query<(Entity, &mut MeshTask, &mut MeshStage, &ChunkPosition), With<Chunk>>,
+  mut query: Query<(Entity, &mut MeshTask, &mut MeshStage, &ChunkPosition, Option<&Children>), With<Chunk>>,
+  section_meshes: Query<&SectionMesh>,
   mut meshes: ResMut<Assets<Mesh>>,
-  mut materials: ResMut<Assets<StandardMaterial>>,
+  mut materials: ResMut<Assets<ChunkMaterial>>,
+  mut chunk_materials: Local<Option<(Handle<ChunkMaterial>, Handle<ChunkMaterial>)>>,
   ref atlas: Res<BlockTextureAtlas>,
 ) {
-  for (entity, mut task, mut stage, position) in query.iter_mut().take(MAX_PROCESSED_FINISHED_BUILD_TASKS_PER_TICK) {
-    if let Some(mesh) = future::block_on(future::poll_once(&mut task.0)) {
+  //All chunks share the same materials
+  let (opaque_material, transparent_material) = chunk_materials.get_or_insert_with(|| {
+    let texture = atlas.0.as_ref().unwrap().texture.as_weak();
+    (
+      materials.add(ChunkMaterial {
+        texture: texture.clone(),
+        alpha_mode: AlphaMode::Mask(0.5),
+      }),
+      materials.add(ChunkMaterial {
+        texture,
+        alpha_mode: AlphaMode::Blend,
+      })
+    )
+  }).clone();
+
+  for (entity, mut task, mut stage, position, children) in query.iter_mut().take(MAX_PROCESSED_FINISHED_BUILD_TASKS_PER_TICK) {
+    if let Some(sections) = future::block_on(future::poll_once(&mut task.0)) {
+
+      //Despawn old meshes of rebuilt sections
+      if let Some(children) = children {
+        for &child in children.iter() {
+          if let Ok(section_mesh) = section_meshes.get(child) {
+            if sections.iter().any(|(section, _)| *section == section_mesh.section) {
+              commands.entity(child).despawn();
+            }
+          }
+        }
+      }
+
       let mut ecmd = commands.entity(entity);
-      //create PbrBundle and Wireframe
-      ecmd.insert_bundle(PbrBundle {
-        mesh: meshes.add(mesh),
-        transform: Transform::from_translation(Vec3::new(
+      //Chunk entity only holds the transform, meshes are stored in children
+      ecmd.insert_bundle(TransformBundle::from_transform(
+        Transform::from_translation(Vec3::new(
           (position.0 * CHUNK_SIZE as i64) as f32, 
           0.0, 
           (position.1 * CHUNK_SIZE as i64) as f32
-        )),
-        material: materials.add(StandardMaterial {
-          base_color: Color::WHITE,
-          base_color_texture: Some(atlas.0.as_ref().unwrap().texture.as_weak()),
-
-          reflectance: 0.,
-          metallic: 0.,
-          perceptual_roughness: 0.5,
-
-          //unlit: true,
-          ..default()
-        }),
-        ..default()
-      }).insert(bevy::pbr::wireframe::Wireframe);
+        ))
+      ));
+      ecmd.with_children(|parent| {
+        for (section, chunk_meshes) in sections {
+          let transform = Transform::from_xyz(0., (section * SECTION_HEIGHT) as f32, 0.);
+          //create MaterialMeshBundle and Wireframe
+          if let Some(opaque) = chunk_meshes.opaque {
+            parent.spawn_bundle(MaterialMeshBundle::<ChunkMaterial> {
+              mesh: meshes.add(opaque),
+              material: opaque_material.clone(),
+              transform,
+              ..default()
+            }).insert(SectionMesh { section }).insert(bevy::pbr::wireframe::Wireframe);
+          }
+          if let Some(transparent) = chunk_meshes.transparent {
+            parent.spawn_bundle(MaterialMeshBundle::<ChunkMaterial> {
+              mesh: meshes.add(transparent),
+              material: transparent_material.clone(),
+              transform,
+              ..default()
+            }).insert(SectionMesh { section });
+          }
+        }
+      });
       //Update MeshStage and remove MeshTask
       ecmd.remove::<MeshTask>();
       *stage = MeshStage::Ready;
@@ -222,21 +556,30 @@ fn apply_mesh_gen_tasks(
 
 fn despawn_world (
   mut commands: Commands,
+  mut chunk_map: ResMut<ChunkMap>,
+  mut light_map: ResMut<LightMap>,
   chunks: Query<Entity, With<Chunk>>
 ) {
   for chunk in chunks.iter() {
-    commands.entity(chunk).despawn();
+    commands.entity(chunk).despawn_recursive();
   }
+  *chunk_map = ChunkMap::default();
+  *light_map = LightMap::default();
 }
 
 pub struct WorldPlugin;
 impl Plugin for WorldPlugin {
   fn build(&self, app: &mut App) {
+    app.init_resource::<ChunkMap>();
+    app.init_resource::<MeshingMode>();
     app.add_system_set(
       ConditionSet::new()
         .label("WorldMain")
         .run_in_bevy_state(AssetLoaderState::Finished)
         .run_in_state(GameState::InGame)
+        .with_system(apply_block_updates)
+        .with_system(mark_neighbours_of_new_chunks)
+        .with_system(toggle_meshing_mode)
         .with_system(mesh_gen_system)
         .with_system(apply_mesh_gen_tasks)
         .into()
@@ -247,7 +590,7 @@ impl Plugin for WorldPlugin {
         .after("WorldMain")
         .run_in_bevy_state(AssetLoaderState::Finished)
         .run_in_state(GameState::InGame)
-        .with_system(update_loaded_chunks_around_player)
+        .with_system(unload_chunks)
         .into()
     );
     app.add_exit_system(
@@ -256,3 +599,70 @@ impl Plugin for WorldPlugin {
     );
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use shared::blocks::{BlockFlags, BlockMetadata};
+
+  fn context() -> MeshContext {
+    let mut block_types = BlockTypeManager::default();
+    block_types.register_multiple([
+      BlockMetadata {
+        key: "air".into(),
+        flags: BlockFlags::FlagAir as u16,
+        shape: BlockShape::None,
+        ..Default::default()
+      },
+      BlockMetadata {
+        key: "stone".into(),
+        textures: vec!["stone".into()],
+        ..Default::default()
+      },
+    ]);
+    MeshContext {
+      block_types,
+      textures: vec![bevy::sprite::Rect { min: Vec2::ZERO, max: Vec2::splat(16.) }],
+      atlas_size: Vec2::splat(16.),
+      tex_map: [("stone".to_string(), 0)].into_iter().collect(),
+    }
+  }
+
+  //A single layer of stone covering the whole bottom of the chunk
+  fn floor(ctx: &MeshContext) -> ChunkNeighbourhood {
+    let stone = Block { state: ctx.block_types.get_by_key("stone").unwrap().default_state() };
+    let mut data = ChunkData::new();
+    for x in 0..CHUNK_SIZE {
+      for z in 0..CHUNK_SIZE {
+        data.set(x, 0, z, stone);
+      }
+    }
+    let mut chunks: [Option<ChunkData>; 9] = Default::default();
+    chunks[4] = Some(data);
+    ChunkNeighbourhood { chunks, lights: Default::default() }
+  }
+
+  fn quad_count(meshes: &ChunkMeshes) -> usize {
+    meshes.opaque.as_ref().map_or(0, |mesh| mesh.count_vertices() / 4)
+  }
+
+  #[test]
+  fn simple_meshing_emits_every_visible_face() {
+    let ctx = context();
+    let meshes = build_section_mesh(0, &floor(&ctx), &ctx, MeshingMode::Simple);
+    //Top and bottom of every block plus the outer sides, neighbouring chunks are not loaded
+    let size = CHUNK_SIZE * CHUNK_SIZE;
+    assert_eq!(quad_count(&meshes), 2 * size + 4 * CHUNK_SIZE);
+    assert!(meshes.transparent.is_none());
+  }
+
+  #[test]
+  fn greedy_meshing_merges_the_same_faces() {
+    let ctx = context();
+    let simple = build_section_mesh(0, &floor(&ctx), &ctx, MeshingMode::Simple);
+    let greedy = build_section_mesh(0, &floor(&ctx), &ctx, MeshingMode::Greedy);
+    assert!(quad_count(&greedy) < quad_count(&simple));
+    //One quad for each side of the floor
+    assert_eq!(quad_count(&greedy), 6);
+  }
+}
@@ -0,0 +1,146 @@
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+use shared::{
+  blocks::BlockTypeManager,
+  messages::ServerToClientMessages,
+  consts::{CHANNEL_BLOCK, REACH_DISTANCE},
+  types::{
+    block::Block,
+    chunk::{ChunkPosition, ChunkMap, ChunkDataComponent},
+    net::Lobby,
+  },
+};
+use crate::{
+  server::{Player, ChunkSubscribers, ChunkMessageCache},
+  storage::DirtyChunk,
+};
+
+//The player position known by the server can be slightly out of date
+const REACH_TOLERANCE: f32 = 1.5;
+
+#[derive(Clone, Copy, Debug)]
+pub enum BlockChange {
+  Break,
+  Place(Block),
+}
+
+//Sent by handle_incoming_stuff, validated and applied by apply_block_changes
+#[derive(Clone, Copy, Debug)]
+pub struct BlockChangeRequest {
+  pub client_id: u64,
+  pub position: (i64, i64, i64),
+  pub change: BlockChange,
+}
+
+//Returns the block that should be placed at the position
+fn validate_change(
+  blocks: &BlockTypeManager,
+  current: Block,
+  change: BlockChange,
+) -> Result<Block, &'static str> {
+  let current = blocks.get_by_state(current.state).ok_or("Unknown block state")?;
+  match change {
+    BlockChange::Break => {
+      if current.is_air() {
+        return Err("Can't break air");
+      }
+      if current.is_unbreakable() {
+        return Err("Block is unbreakable");
+      }
+      Ok(Block::AIR)
+    },
+    BlockChange::Place(block) => {
+      if !(current.is_air() || current.is_liquid()) {
+        return Err("Position is occupied");
+      }
+      match blocks.get_by_state(block.state) {
+        None => Err("Invalid block state"),
+        Some(metadata) if metadata.is_air() => Err("Can't place air"),
+        Some(_) => Ok(block)
+      }
+    }
+  }
+}
+
+fn apply_block_changes(
+  mut commands: Commands,
+  mut requests: EventReader<BlockChangeRequest>,
+  mut server: ResMut<RenetServer>,
+  blocks: Res<BlockTypeManager>,
+  lobby: Res<Lobby>,
+  chunk_map: Res<ChunkMap>,
+  players: Query<&Transform, With<Player>>,
+  mut chunks: Query<(&mut ChunkDataComponent, &ChunkSubscribers, &mut ChunkMessageCache)>,
+) {
+  for request in requests.iter() {
+    let (x, y, z) = request.position;
+    let (chunk_position, (bx, by, bz)) = match ChunkPosition::from_block(x, y, z) {
+      Some(located) => located,
+      None => {
+        warn!("Client {} tried to modify a block outside of the world", request.client_id);
+        continue
+      }
+    };
+    //Chunk must be loaded and generated
+    let entity = match chunk_map.get(chunk_position) {
+      Some(entity) => entity,
+      None => {
+        warn!("Client {} tried to modify a block in an unloaded chunk", request.client_id);
+        continue
+      }
+    };
+    let (mut data, subscribers, mut cache) = match chunks.get_mut(entity) {
+      Ok(chunk) => chunk,
+      Err(_) => {
+        warn!("Client {} tried to modify a block in a chunk that is still generating", request.client_id);
+        continue
+      }
+    };
+    let current = data.0.get(bx, by, bz);
+
+    //Check reach distance
+    let in_reach = lobby.players.get(&request.client_id)
+      .and_then(|&player| players.get(player).ok())
+      .map_or(false, |transform| {
+        let center = Vec3::new(x as f32, y as f32, z as f32) + Vec3::splat(0.5);
+        transform.translation.distance(center) <= REACH_DISTANCE + REACH_TOLERANCE
+      });
+
+    let result = match in_reach {
+      true => validate_change(&blocks, current, request.change),
+      false => Err("Block is out of reach")
+    };
+    match result {
+      Ok(block) => {
+        data.0.set(bx, by, bz, block);
+        cache.invalidate();
+        commands.entity(entity).insert(DirtyChunk);
+        let message = bincode::serialize(&ServerToClientMessages::BlockUpdate {
+          position: request.position, block
+        }).unwrap();
+        //Same channel as the chunk data, so the update can't arrive before the chunk it's for
+        for &client_id in subscribers.0.iter() {
+          server.send_message(client_id, CHANNEL_BLOCK, message.clone());
+        }
+      },
+      Err(reason) => {
+        warn!("Rejected block change from client {}: {}", request.client_id, reason);
+        //Send the actual block back, so the client can revert its local change
+        server.send_message(
+          request.client_id, CHANNEL_BLOCK,
+          bincode::serialize(&ServerToClientMessages::BlockUpdate {
+            position: request.position, block: current
+          }).unwrap()
+        );
+      }
+    }
+  }
+}
+
+pub struct BlockUpdatePlugin;
+impl Plugin for BlockUpdatePlugin {
+  fn build(&self, app: &mut App) {
+    app.add_event::<BlockChangeRequest>();
+    app.add_system(apply_block_changes);
+  }
+}
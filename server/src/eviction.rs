@@ -0,0 +1,106 @@
+use bevy::prelude::*;
+use bevy::tasks::AsyncComputeTaskPool;
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+use shared::types::chunk::{ChunkPosition, ChunkMap, ChunkDataComponent};
+use crate::{
+  Args,
+  server::ChunkSubscribers,
+  server::ChunkMessageCache,
+  storage::{WorldStorage, DirtyChunk, SavingChunk, PendingSaves},
+};
+
+//Seconds between eviction passes
+const EVICTION_INTERVAL: f64 = 1.;
+
+//Amount of chunks in the ChunkMap (including ones that are still generating), shared with the HTTP API
+#[derive(Default, Clone)]
+pub struct ResidentChunks(pub Arc<AtomicUsize>);
+
+//Time the chunk lost its last subscriber at
+#[derive(Component, Debug, Clone, Copy)]
+pub struct IdleSince(f64);
+
+fn track_idle_chunks(
+  mut commands: Commands,
+  time: Res<Time>,
+  chunks: Query<(Entity, &ChunkSubscribers, Option<&IdleSince>), With<ChunkDataComponent>>,
+) {
+  let now = time.seconds_since_startup();
+  for (entity, subscribers, idle) in chunks.iter() {
+    match (subscribers.0.is_empty(), idle.is_some()) {
+      (true, false) => { commands.entity(entity).insert(IdleSince(now)); },
+      (false, true) => { commands.entity(entity).remove::<IdleSince>(); },
+      _ => ()
+    }
+  }
+}
+
+//Chunks without subscribers are unloaded once they've been idle for long enough,
+//or earlier (longest idle first) if there are more chunks than the server is allowed to keep
+fn evict_chunks(
+  mut commands: Commands,
+  time: Res<Time>,
+  args: Res<Args>,
+  storage: Res<WorldStorage>,
+  pool: Res<AsyncComputeTaskPool>,
+  mut pending: ResMut<PendingSaves>,
+  mut chunk_map: ResMut<ChunkMap>,
+  chunks: Query<(Entity, &ChunkPosition, &ChunkSubscribers, &IdleSince, &ChunkDataComponent, &ChunkMessageCache, Option<&DirtyChunk>, Option<&SavingChunk>)>,
+  mut last_run: Local<f64>,
+) {
+  let now = time.seconds_since_startup();
+  if now - *last_run < EVICTION_INTERVAL {
+    return;
+  }
+  *last_run = now;
+  let timeout = args.chunk_idle_timeout as f64;
+  let over_limit = args.max_chunks.map_or(0, |max| chunk_map.len().saturating_sub(max));
+
+  let mut idle: Vec<_> = chunks.iter()
+    .filter(|(_, _, subscribers, ..)| subscribers.0.is_empty())
+    .collect();
+  idle.sort_by(|a, b| a.3.0.total_cmp(&b.3.0));
+
+  //Dirty chunks are saved first and evicted once the save succeeded and they're clean
+  //Chunks whose save failed stay dirty, so they're never evicted before they're on disk
+  let mut unsaved = Vec::new();
+  let mut evicted = 0;
+  for (index, (entity, position, _, since, data, cache, dirty, saving)) in idle.into_iter().enumerate() {
+    if index >= over_limit && now - since.0 < timeout {
+      break;
+    }
+    match (dirty.is_some(), saving.is_some()) {
+      (_, true) => (),
+      (true, false) => unsaved.push((entity, *position, data.0.clone(), cache.revision())),
+      (false, false) => {
+        commands.entity(entity).despawn();
+        chunk_map.remove(*position);
+        evicted += 1;
+      }
+    }
+  }
+  if !unsaved.is_empty() {
+    info!("Saving {} chunks before unloading them", unsaved.len());
+    pending.save(&mut commands, &pool, &storage, unsaved);
+  }
+  if evicted > 0 {
+    info!("Unloaded {} idle chunks, {} chunks left", evicted, chunk_map.len());
+  }
+}
+
+fn update_resident_chunks(
+  chunk_map: Res<ChunkMap>,
+  resident: Res<ResidentChunks>,
+) {
+  resident.0.store(chunk_map.len(), Ordering::Relaxed);
+}
+
+pub struct ChunkEvictionPlugin;
+impl Plugin for ChunkEvictionPlugin {
+  fn build(&self, app: &mut App) {
+    app.init_resource::<ResidentChunks>();
+    app.add_system(track_idle_chunks);
+    app.add_system(evict_chunks);
+    app.add_system(update_resident_chunks);
+  }
+}
@@ -15,9 +15,10 @@ use serde_json::json;
 
 use std::{
   net::SocketAddr, time::SystemTime,
+  sync::atomic::Ordering,
 };
 use crate::{
-  Args, server::PrivateKey
+  Args, server::PrivateKey, eviction::ResidentChunks
 };
 use shared::{
   consts::PROTOCOL_ID,
@@ -70,10 +71,12 @@ fn connect_reply_validation_fail(error: &'static str) -> ConnectReply {
 fn start(
   pool: Res<AsyncComputeTaskPool>,
   args: Res<Args>,
-  private_key: Res<PrivateKey>
+  private_key: Res<PrivateKey>,
+  resident_chunks: Res<ResidentChunks>,
 ) {
   let args = args.clone();
   let private_key = private_key.0;
+  let resident_chunks = resident_chunks.clone();
   pool.spawn(async move {
     let runtime = TokioRuntime::new().unwrap();
     runtime.block_on(async move {
@@ -88,6 +91,12 @@ fn start(
         }))
       });
 
+      let metrics = warp::path!("metrics").map(move || {
+        warp::reply::json(&json!({
+          "resident_chunks": resident_chunks.0.load(Ordering::Relaxed),
+        }))
+      });
+
       let connect = 
         warp::path!("connect")
         .and(warp::query::<HashMap<String, String>>())
@@ -159,7 +168,7 @@ fn start(
           }
         });
 
-      let api = connect.or(root);
+      let api = connect.or(metrics).or(root);
 
       let port = args.port_api;
       info!("API Port: {}", port);
@@ -8,19 +8,31 @@ use bevy::{
 use clap::Parser;
 use std::{
   net::IpAddr, 
+  path::PathBuf,
   time::Duration,
 };
 use shared::{
-  consts::DEFAULT_PORT,
-  blocks::BlockManagerPlugin
+  consts::{DEFAULT_PORT, SERVER_TICK_RATE},
+  blocks::{BlockTypeManager, default_blocks_dir},
+  physics::FLY_SPEED,
 };
 
 pub(crate) mod server;
 pub(crate) mod http_server;
 pub(crate) mod worldgen;
+pub(crate) mod storage;
+pub(crate) mod block_updates;
+pub(crate) mod movement;
+pub(crate) mod streaming;
+pub(crate) mod eviction;
 
 use server::ServerPlugin;
 use http_server::HttpServerPlugin;
+use storage::WorldStoragePlugin;
+use block_updates::BlockUpdatePlugin;
+use movement::MovementPlugin;
+use streaming::StreamingPlugin;
+use eviction::ChunkEvictionPlugin;
 
 #[derive(Parser, Debug, Clone)]
 #[clap()]
@@ -33,23 +45,61 @@ struct Args {
 
   #[clap(long, value_parser, default_value_t = DEFAULT_PORT + 1)]
   port_server: u16,
+
+  /// Block definitions directory, "blocks" next to the executable or in the working directory by default
+  #[clap(long, value_parser)]
+  blocks: Option<PathBuf>,
+
+  #[clap(long, value_parser, default_value = "world")]
+  world: PathBuf,
+
+  /// Autosave interval in seconds
+  #[clap(long, value_parser, default_value_t = 60)]
+  autosave: u64,
+
+  /// Max player speed in blocks per second, faster movement gets corrected
+  #[clap(long, value_parser, default_value_t = FLY_SPEED)]
+  max_speed: f32,
+
+  /// Seconds a chunk stays loaded after the last player stopped watching it
+  #[clap(long, value_parser, default_value_t = 300)]
+  chunk_idle_timeout: u64,
+
+  /// Max amount of loaded chunks, idle chunks get unloaded early to stay below it
+  #[clap(long, value_parser)]
+  max_chunks: Option<usize>,
 }
 
 fn main() {
   let mut app = App::new();
 
-  app.insert_resource(Args::parse());
+  let args = Args::parse();
+  let blocks_dir = args.blocks.clone().unwrap_or_else(default_blocks_dir);
+  app.insert_resource(args);
 
   app.add_plugins(MinimalPlugins);
   app.add_plugin(LogPlugin);
   app.add_plugin(TransformPlugin);
   app.add_plugin(HierarchyPlugin);
 
+  let blocks = match BlockTypeManager::load(&blocks_dir) {
+    Ok(blocks) => blocks,
+    Err(error) => {
+      error!("{}", error);
+      std::process::exit(1);
+    }
+  };
+  app.insert_resource(blocks);
+
   app.insert_resource(bevy::tasks::TaskPoolBuilder::new().build());
-  app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(1./60.)));
+  app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(1. / SERVER_TICK_RATE as f64)));
 
-  app.add_plugin(BlockManagerPlugin);
+  app.add_plugin(WorldStoragePlugin);
   app.add_plugin(ServerPlugin);
+  app.add_plugin(BlockUpdatePlugin);
+  app.add_plugin(MovementPlugin);
+  app.add_plugin(StreamingPlugin);
+  app.add_plugin(ChunkEvictionPlugin);
   app.add_plugin(HttpServerPlugin);
 
   app.run();
@@ -0,0 +1,224 @@
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+use shared::{
+  blocks::BlockTypeManager,
+  messages::ServerToClientMessages,
+  consts::{CHANNEL_RELIABLE, CHANNEL_UNRELIABLE},
+  physics::{Aabb, PLAYER_EYE_HEIGHT, TERMINAL_VELOCITY, sweep, move_player},
+  types::{
+    chunk::{ChunkPosition, ChunkMap, ChunkDataComponent},
+    net::Lobby,
+    player::Orientation,
+  },
+};
+use crate::{
+  Args,
+  server::{Player, ServerTick},
+};
+
+//Extra distance allowed on top of the speed limit, covers network jitter
+const MOVE_TOLERANCE: f32 = 0.5;
+//Players can't save up more than this many seconds worth of movement by standing still
+const MAX_MOVE_INTERVAL: f32 = 0.5;
+//Distance the client position may be off from the swept one before it gets corrected
+const SWEEP_TOLERANCE: f32 = 0.05;
+//Players closer to the ground than this can step up blocks
+const GROUND_DISTANCE: f32 = 0.01;
+//Violations are counted over this many seconds
+const VIOLATION_WINDOW: f64 = 10.;
+//Log a warning every time a player reaches this many violations within the window
+const VIOLATION_WARN_THRESHOLD: u32 = 10;
+
+//Sent by handle_incoming_stuff, validated and applied by validate_player_movement
+#[derive(Clone, Copy, Debug)]
+pub struct PlayerMoveRequest {
+  pub client_id: u64,
+  pub new_pos: Vec3,
+  pub orientation: Orientation,
+  pub sequence: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MoveViolation {
+  TooFast,
+  Blocked,
+  UnloadedChunk,
+}
+
+//Distance a player can still move, refilled over time and used up by moves,
+//so sending lots of messages doesn't allow moving any faster
+#[derive(Clone, Copy, Debug, Default)]
+struct MoveBudget {
+  horizontal: f32,
+  up: f32,
+  down: f32,
+}
+impl MoveBudget {
+  fn refill(&mut self, elapsed: f32, max_speed: f32) {
+    let refill = |budget: f32, speed: f32| (budget + speed * elapsed).min(speed * MAX_MOVE_INTERVAL + MOVE_TOLERANCE);
+    self.horizontal = refill(self.horizontal, max_speed);
+    self.up = refill(self.up, max_speed);
+    self.down = refill(self.down, TERMINAL_VELOCITY);
+  }
+
+  //Clamps the movement to the budget and uses it up, returns true if it had to be clamped
+  fn take(&mut self, delta: &mut Vec3) -> bool {
+    let mut clamped = false;
+    let horizontal = Vec2::new(delta.x, delta.z);
+    if horizontal.length() > self.horizontal {
+      let horizontal = horizontal.clamp_length_max(self.horizontal);
+      delta.x = horizontal.x;
+      delta.z = horizontal.y;
+      clamped = true;
+    }
+    if delta.y > self.up || delta.y < -self.down {
+      delta.y = delta.y.clamp(-self.down, self.up);
+      clamped = true;
+    }
+    self.horizontal -= Vec2::new(delta.x, delta.z).length();
+    self.up -= delta.y.max(0.);
+    self.down -= (-delta.y).max(0.);
+    clamped
+  }
+}
+
+#[derive(Component, Debug, Default)]
+pub struct MovementState {
+  //Time of the last move request
+  last_move: f64,
+  budget: MoveBudget,
+  //Sequence number of the last move request, requests that arrive out of order are dropped
+  last_sequence: Option<u32>,
+  violations: u32,
+  window_start: f64,
+}
+impl MovementState {
+  //Returns the amount of violations in the current window
+  fn record_violation(&mut self, now: f64) -> u32 {
+    if now - self.window_start > VIOLATION_WINDOW {
+      self.window_start = now;
+      self.violations = 0;
+    }
+    self.violations += 1;
+    self.violations
+  }
+}
+
+fn validate_player_movement(
+  mut requests: EventReader<PlayerMoveRequest>,
+  mut server: ResMut<RenetServer>,
+  time: Res<Time>,
+  tick: Res<ServerTick>,
+  args: Res<Args>,
+  lobby: Res<Lobby>,
+  blocks: Res<BlockTypeManager>,
+  chunk_map: Res<ChunkMap>,
+  chunks: Query<&ChunkDataComponent>,
+  mut players: Query<(&mut Transform, &mut MovementState), With<Player>>,
+) {
+  let now = time.seconds_since_startup();
+
+  //None if the block is in a chunk that is not loaded
+  let is_solid = |(x, y, z): (i64, i64, i64)| -> Option<bool> {
+    match ChunkPosition::from_block(x, y, z) {
+      Some((position, (bx, by, bz))) => {
+        let data = chunks.get(chunk_map.get(position)?).ok()?;
+        Some(blocks.get_by_state(data.0.get(bx, by, bz).state).map_or(true, |meta| meta.is_solid()))
+      },
+      None => Some(y < 0)
+    }
+  };
+  //Blocks the swept path passes through have to be loaded
+  let path_loaded = |from: Aabb, to: Aabb| {
+    Aabb::new(from.min.min(to.min), from.max.max(to.max)).blocks().all(|block| is_solid(block).is_some())
+  };
+  let is_solid = |block| is_solid(block).unwrap_or(true);
+
+  for request in requests.iter() {
+    let entity = match lobby.players.get(&request.client_id) {
+      Some(&entity) => entity,
+      None => continue
+    };
+    let (mut transform, mut state) = match players.get_mut(entity) {
+      Ok(player) => player,
+      Err(_) => continue
+    };
+    if !(request.new_pos.is_finite() && request.orientation.is_finite()) {
+      warn!("Client {} sent an invalid position", request.client_id);
+      continue;
+    }
+    if state.last_sequence.map_or(false, |last| request.sequence <= last) {
+      continue;
+    }
+    state.last_sequence = Some(request.sequence);
+    let old_pos = transform.translation;
+    let elapsed = (now - state.last_move) as f32;
+    state.last_move = now;
+    state.budget.refill(elapsed, args.max_speed);
+    let mut violation = None;
+
+    //Clamp the movement to the speed limit, falling is limited by terminal velocity instead
+    let mut delta = request.new_pos - old_pos;
+    if state.budget.take(&mut delta) {
+      violation = Some(MoveViolation::TooFast);
+    }
+
+    //Move the player along the path the same way the client does, so it can't pass through blocks
+    //Blocks it's already stuck in are ignored, so it can still get out of them
+    let feet = old_pos - Vec3::Y * PLAYER_EYE_HEIGHT;
+    let mut new_pos = old_pos;
+    if path_loaded(Aabb::player(feet), Aabb::player(feet + delta)) {
+      let on_ground = sweep(Aabb::player(feet), Vec3::Y * -GROUND_DISTANCE, &is_solid).on_ground;
+      let swept = move_player(feet, delta, on_ground, &is_solid).movement;
+      new_pos = old_pos + swept;
+      if swept.distance(delta) > SWEEP_TOLERANCE {
+        violation = Some(MoveViolation::Blocked);
+      }
+    } else {
+      violation = Some(MoveViolation::UnloadedChunk);
+    }
+
+    //Looking around is never a violation
+    let orientation = request.orientation.normalized();
+    let old_orientation = Orientation::from_rotation(transform.rotation);
+    transform.rotation = orientation.to_rotation();
+
+    if new_pos != old_pos || orientation != old_orientation {
+      transform.translation = new_pos;
+      server.broadcast_message_except(
+        request.client_id, CHANNEL_UNRELIABLE,
+        bincode::serialize(&ServerToClientMessages::PlayerSync {
+          id: request.client_id,
+          new_pos,
+          orientation,
+          tick: tick.0,
+        }).unwrap()
+      );
+    }
+
+    if let Some(reason) = violation {
+      debug!("Corrected movement of client {}: {:?}", request.client_id, reason);
+      server.send_message(
+        request.client_id, CHANNEL_RELIABLE,
+        bincode::serialize(&ServerToClientMessages::PositionCorrection {
+          position: new_pos
+        }).unwrap()
+      );
+      let violations = state.record_violation(now);
+      if violations % VIOLATION_WARN_THRESHOLD == 0 {
+        warn!(
+          "Client {} sent {} invalid moves in the last {} seconds (last one: {:?})",
+          request.client_id, violations, VIOLATION_WINDOW, reason
+        );
+      }
+    }
+  }
+}
+
+pub struct MovementPlugin;
+impl Plugin for MovementPlugin {
+  fn build(&self, app: &mut App) {
+    app.add_event::<PlayerMoveRequest>();
+    app.add_system(validate_player_movement);
+  }
+}
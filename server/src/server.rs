@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 use bevy::tasks::{Task, AsyncComputeTaskPool};
+use bevy::utils::{HashSet, HashMap};
 use bevy_renet::{
   renet::{
     RenetServer, 
@@ -25,7 +26,7 @@ use shared::{
   blocks::BlockTypeManager,
   messages::{ServerToClientMessages, ClientToServerMessages},
   consts::{ 
-    PROTOCOL_ID, MAX_CLIENTS, CHANNEL_RELIABLE, CHANNEL_UNRELIABLE,
+    PROTOCOL_ID, MAX_CLIENTS, CHANNEL_RELIABLE, CHANNEL_BLOCK,
     renet_connection_config
   },
   utils::{
@@ -35,11 +36,18 @@ use shared::{
   types::{
     chunk::{Chunk, ChunkData, ChunkPosition, ChunkMap, ChunkDataComponent},
     net::{AuthUserData, Lobby},
-    player::{PlayerInitData, Username},
+    player::{PlayerInitData, Username, Orientation},
     chat::ChatMessage,
   },
 };
-use crate::{Args, worldgen::generate as generate_chunk};
+use crate::{
+  Args,
+  worldgen::generate as generate_chunk,
+  storage::{WorldStorage, DirtyChunk},
+  block_updates::{BlockChangeRequest, BlockChange},
+  movement::{PlayerMoveRequest, MovementState},
+  streaming::{ChunkResendRequest, ViewDistanceRequest, ClientChunks},
+};
 
 pub struct PrivateKey(pub [u8; NETCODE_KEY_BYTES]);
 
@@ -48,6 +56,18 @@ pub struct Player { pub id: u64 }
 
 pub struct SendSysMessageEvt(pub String);
 
+//Clients that have the chunk loaded (or will receive it once it's generated)
+#[derive(Component, Default, Debug)]
+pub struct ChunkSubscribers(pub HashSet<u64>);
+
+//Incremented at the start of every server update
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ServerTick(pub u64);
+
+fn advance_tick(mut tick: ResMut<ServerTick>) {
+  tick.0 += 1;
+}
+
 fn create_renet_server(
   mut commands: Commands, 
   args: Res<Args>,
@@ -88,7 +108,9 @@ fn server_update_system(
   mut lobby: ResMut<Lobby>,
   mut server: ResMut<RenetServer>,
   mut sys_msg: EventWriter<SendSysMessageEvt>,
-  players: Query<(&Player, &Username, &GlobalTransform)>
+  blocks: Res<BlockTypeManager>,
+  players: Query<(&Player, &Username, &GlobalTransform)>,
+  mut chunk_subscribers: Query<&mut ChunkSubscribers>,
 ) {
   'evt_loop: for event in server_events.iter() {
     match event {
@@ -122,6 +144,8 @@ fn server_update_system(
           .insert_bundle(TransformBundle::from_transform(plr_transform))
           .insert(Player { id: *id })
           .insert(Username(username.clone()))
+          .insert(MovementState::default())
+          .insert(ClientChunks::default())
           .id();
         
         //Insert it into Lobby
@@ -133,6 +157,7 @@ fn server_update_system(
           bincode::serialize(&ServerToClientMessages::InitData {
             self_init: PlayerInitData { 
               position: plr_transform.translation,
+              orientation: Orientation::from_rotation(plr_transform.rotation),
               username: username.clone()
             },
             player_init: {
@@ -143,6 +168,7 @@ fn server_update_system(
                   player.id, 
                   PlayerInitData {
                     position: transform.translation,
+                    orientation: Orientation::from_rotation(transform.rotation),
                     username: name.0.clone()
                   }
                 ));
@@ -150,6 +176,7 @@ fn server_update_system(
               player_init
             },
             chat_messages: Vec::new(), //TODO sync chat
+            block_palette: blocks.palette(),
           }).unwrap()
         );
 
@@ -161,6 +188,7 @@ fn server_update_system(
             id: *id,
             init_data: PlayerInitData {
               position: plr_transform.translation,
+              orientation: Orientation::from_rotation(plr_transform.rotation),
               username: username.clone()
             }
           }).unwrap()
@@ -182,6 +210,11 @@ fn server_update_system(
           commands.entity(player_entity).despawn();
         }
 
+        //Unsubscribe from all chunks
+        for mut subscribers in chunk_subscribers.iter_mut() {
+          subscribers.0.remove(id);
+        }
+
         //Broadcast disconnect message
         server.broadcast_message_except(
           *id, CHANNEL_RELIABLE, 
@@ -197,52 +230,229 @@ fn server_update_system(
   }
 }
 
+//Serialized ChunkData message of a loaded chunk, so it doesn't have to be compressed again for every client
+#[derive(Component, Default)]
+pub struct ChunkMessageCache {
+  message: Option<Vec<u8>>,
+  //Bumped on every change, so compress tasks started before it can't fill the cache with outdated data
+  revision: u32,
+}
+impl ChunkMessageCache {
+  #[inline] pub fn get(&self) -> Option<&Vec<u8>> {
+    self.message.as_ref()
+  }
+  #[inline] pub fn revision(&self) -> u32 {
+    self.revision
+  }
+  pub fn invalidate(&mut self) {
+    self.message = None;
+    self.revision = self.revision.wrapping_add(1);
+  }
+  fn fill(&mut self, revision: u32, message: Vec<u8>) {
+    if revision == self.revision {
+      self.message = Some(message);
+    }
+  }
+}
+
 #[derive(Component)]
-struct ChunkCompressTask{
+pub struct ChunkCompressTask{
   pub task: Task<Vec<u8>>,
-  pub client_id: u64,
+  pub clients: Vec<u64>,
+  //Chunk entity and the cache revision the data was taken at
+  pub chunk: Entity,
+  pub revision: u32,
+}
+
+pub struct ChunkGenResult {
+  pub chunk: ChunkData,
+  pub message: Vec<u8>,
+  //false if the chunk was loaded from disk
+  pub generated: bool,
 }
 
 #[derive(Component)]
-struct ChunkGenTask{
-  pub task: Task<(ChunkData, Vec<u8>)>,
-  pub subscribers: Vec<u64>,
+pub struct ChunkGenTask{
+  pub task: Task<ChunkGenResult>,
 }
 
 fn process_chunk_gen_tasks(
   mut commands: Commands,
   mut server: ResMut<RenetServer>,
-  mut tasks: Query<(Entity, &mut ChunkGenTask)>
+  mut tasks: Query<(Entity, &mut ChunkGenTask, &ChunkSubscribers, &mut ChunkMessageCache)>
 ) {
-  for (entity, mut task) in tasks.iter_mut() {
-    if let Some((chunk, message)) = future::block_on(future::poll_once(&mut task.task)) {
-      if task.subscribers.len() == 1 {
-        //Send without cloning
-        server.send_message(task.subscribers[0], CHANNEL_UNRELIABLE, message);
-      } else {
-        //If multiple clients are subscribed, clone message
-        for client_id in task.subscribers.iter() {
-          server.send_message(*client_id, CHANNEL_UNRELIABLE, message.clone());
-        }
+  for (entity, mut task, subscribers, mut cache) in tasks.iter_mut() {
+    if let Some(ChunkGenResult { chunk, message, generated }) = future::block_on(future::poll_once(&mut task.task)) {
+      for client_id in subscribers.0.iter() {
+        server.send_message(*client_id, CHANNEL_BLOCK, message.clone());
+      }
+      let revision = cache.revision;
+      cache.fill(revision, message);
+      let mut ecmd = commands.entity(entity);
+      ecmd.remove::<ChunkGenTask>().insert(ChunkDataComponent(chunk));
+      if generated {
+        ecmd.insert(DirtyChunk);
       }
-      commands.entity(entity).remove::<ChunkGenTask>().insert(ChunkDataComponent(chunk));
     }; 
   }
 }
 
+//Dropping the task cancels it, so chunks nobody is waiting for anymore aren't generated
+fn cancel_abandoned_gen_tasks(
+  mut commands: Commands,
+  mut chunk_map: ResMut<ChunkMap>,
+  tasks: Query<(Entity, &ChunkPosition, &ChunkSubscribers), With<ChunkGenTask>>,
+) {
+  for (entity, position, subscribers) in tasks.iter() {
+    if subscribers.0.is_empty() {
+      info!("Chunk {:?} - Generation cancelled, no subscribers left", position);
+      commands.entity(entity).despawn();
+      chunk_map.remove(*position);
+    }
+  }
+}
+
 fn process_chunk_compress_tasks(
   mut commands: Commands,
   mut server: ResMut<RenetServer>,
-  mut tasks: Query<(Entity, &mut ChunkCompressTask)>
+  pool: Res<AsyncComputeTaskPool>,
+  mut tasks: Query<(Entity, &mut ChunkCompressTask)>,
+  mut chunks: Query<(&ChunkPosition, &ChunkDataComponent, &ChunkSubscribers, &mut ChunkMessageCache)>,
 ) {
   for (entity, mut task) in tasks.iter_mut() {
     if let Some(message) = future::block_on(future::poll_once(&mut task.task)) {
-      server.send_message(task.client_id, CHANNEL_UNRELIABLE, message);
       commands.entity(entity).remove::<ChunkCompressTask>().despawn();
+      //The chunk might have been unloaded in the meantime
+      let (position, data, subscribers, mut cache) = match chunks.get_mut(task.chunk) {
+        Ok(chunk) => chunk,
+        Err(_) => continue
+      };
+      //Clients that got unsubscribed while the task was running already got an UnloadChunk
+      let clients: Vec<u64> = task.clients.iter().copied().filter(|client_id| subscribers.0.contains(client_id)).collect();
+      if task.revision == cache.revision {
+        for &client_id in clients.iter() {
+          server.send_message(client_id, CHANNEL_BLOCK, message.clone());
+        }
+        cache.fill(task.revision, message);
+      } else if let Some(message) = cache.get() {
+        //The chunk changed while compressing, but another task already cached the new data
+        for client_id in clients {
+          server.send_message(client_id, CHANNEL_BLOCK, message.clone());
+        }
+      } else if !clients.is_empty() {
+        //The chunk changed while compressing, the outdated message must not be sent
+        commands.spawn().insert(compress_task(&pool, *position, &data.0, clients, task.chunk, cache.revision));
+      }
     }; 
   }
 }
 
+fn compress_task(
+  pool: &AsyncComputeTaskPool,
+  position: ChunkPosition,
+  data: &ChunkData,
+  clients: Vec<u64>,
+  chunk: Entity,
+  revision: u32,
+) -> ChunkCompressTask {
+  let data: ChunkData = data.clone();
+  ChunkCompressTask {
+    clients,
+    chunk,
+    revision,
+    task: pool.spawn(async move {
+      bincode::serialize(&ServerToClientMessages::ChunkData { 
+        data: data.into(), 
+        position: (position.0, position.1)
+      }).unwrap()
+    })
+  }
+}
+
+//Subscribes a client to a chunk, it's sent to the client as soon as it's loaded or generated
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkLoadRequest {
+  pub client_id: u64,
+  pub position: ChunkPosition,
+}
+
+fn load_requested_chunks(
+  mut commands: Commands,
+  mut requests: EventReader<ChunkLoadRequest>,
+  mut server: ResMut<RenetServer>,
+  pool: Res<AsyncComputeTaskPool>,
+  blocks: Res<BlockTypeManager>,
+  storage: Res<WorldStorage>,
+  mut chunk_map: ResMut<ChunkMap>,
+  mut chunk_query: Query<(Option<&ChunkDataComponent>, &mut ChunkSubscribers, &ChunkMessageCache), With<Chunk>>,
+) {
+  //Chunk entities spawned here don't exist until the end of the stage,
+  //so all requests for the same chunk have to be handled at once
+  let mut requested: HashMap<ChunkPosition, Vec<u64>> = HashMap::default();
+  for request in requests.iter() {
+    requested.entry(request.position).or_default().push(request.client_id);
+  }
+
+  for (position, clients) in requested {
+    let ChunkPosition(x, y) = position;
+    info!("Chunk {:?} requested by {:?}", position, clients);
+    if let Some(chunk) = chunk_map.get(position) {
+      let (data, mut subscribers, cache) = chunk_query.get_mut(chunk).unwrap();
+      subscribers.0.extend(clients.iter().copied());
+      if let Some(message) = cache.get() {
+        info!("^ Cached");
+        for client_id in clients {
+          server.send_message(client_id, CHANNEL_BLOCK, message.clone());
+        }
+      } else if let Some(data) = data {
+        //If the requested chunk is ready, start a compression task
+        //That sends the chunk data and fills the cache after completion
+        info!("^ ChunkCompressTask");
+        commands.spawn().insert(compress_task(&pool, position, &data.0, clients, chunk, cache.revision));
+      } else {
+        //If the requested chunk is not generated yet,
+        //the clients are going to receive it once it's done
+        info!("^ GenTaskSub");
+      }
+    } else {
+      //Spawn chunk gen task
+      //(Tries to load the chunk from disk first)
+      info!("^ NewGenTask");
+      let blocks_uwu = blocks.clone();
+      let storage = storage.clone();
+      let task = pool.spawn(async move {
+        let (chunk, generated) = match storage.load_chunk(x, y) {
+          Ok(Some(chunk)) => (chunk, false),
+          Ok(None) => (generate_chunk(x, y, &blocks_uwu), true),
+          //The new chunk isn't marked dirty, so the stored one only gets replaced if a player modifies it,
+          //and the region file is copied aside before that can happen
+          Err(error) => {
+            error!("Failed to load chunk ({}, {}), generating a new one: {}", x, y, error);
+            match storage.backup_region(x, y) {
+              Ok(path) => warn!("Region file of chunk ({}, {}) copied to {:?}", x, y, path),
+              Err(error) => error!("Failed to back up the region file of chunk ({}, {}): {}", x, y, error),
+            }
+            (generate_chunk(x, y, &blocks_uwu), false)
+          }
+        };
+        let cumpressed = bincode::serialize(&ServerToClientMessages::ChunkData { 
+          data: chunk.clone().into(), 
+          position: (x, y)
+        }).unwrap();
+        ChunkGenResult { chunk, message: cumpressed, generated }
+      });
+      //Spawn Chunk entity
+      let entity = commands.spawn()
+        .insert(Chunk)
+        .insert(position)
+        .insert(ChunkSubscribers(HashSet::from_iter(clients)))
+        .insert(ChunkMessageCache::default())
+        .insert(ChunkGenTask { task }).id();
+      chunk_map.insert(position, entity);
+    }
+  }
+}
+
 fn send_system_messages(
   mut events: EventReader<SendSysMessageEvt>,
   mut server: ResMut<RenetServer>,
@@ -264,73 +474,25 @@ fn send_system_messages(
 
 //TODO!!! Server: Separate into multiple systems
 fn handle_incoming_stuff(
-  mut commands: Commands,
   mut server: ResMut<RenetServer>,
-  pool: Res<AsyncComputeTaskPool>,
-  blocks: Res<BlockTypeManager>,
   lobby: Res<Lobby>,
-  mut players: Query<(&mut Transform, &Username), With<Player>>,
-  mut chunk_map: ResMut<ChunkMap>,
-  mut chunk_query: Query<(Option<&ChunkDataComponent>, Option<&mut ChunkGenTask>), With<Chunk>>
+  players: Query<&Username, With<Player>>,
+  mut block_changes: EventWriter<BlockChangeRequest>,
+  mut move_requests: EventWriter<PlayerMoveRequest>,
+  mut chunk_resends: EventWriter<ChunkResendRequest>,
+  mut view_distances: EventWriter<ViewDistanceRequest>,
 ) {
   for client_id in server.clients_id() {
     for channel_id in 0..=2 {
       while let Some(message) = server.receive_message(client_id, channel_id) {
         if let Ok(message) = bincode::deserialize(&message) {
           match message {
-            ClientToServerMessages::ChunkRequest {x, y} => {
-              info!("Chunk request {} {}", x, y);
-              let pos = ChunkPosition(x, y);
-              if let Some(chunk) = chunk_map.get(pos) {
-                let query_result = chunk_query.get_mut(chunk).unwrap();
-                if let Some(data) = query_result.0 {
-                  //If the requested chunk is ready, start a compression task
-                  //That sends the chunk data after completion
-                  info!("^ ChunkCompressTask");
-                  let data: ChunkData = data.0.clone();
-                  commands.spawn().insert(ChunkCompressTask {
-                    client_id,
-                    task: pool.spawn(async move {
-                      //still broken
-                      std::thread::sleep(std::time::Duration::from_millis(50));
-                      bincode::serialize(&ServerToClientMessages::ChunkData { 
-                        data: data.into(), 
-                        position: (x, y)
-                      }).unwrap()
-                    })
-                  });
-                } else if let Some(mut task) = query_result.1 {
-                  //If the requested chunk is not generated yet, subscribe client to it
-                  //(...Only if it's not already subscribed)
-                  info!("^ GenTaskSub");
-                  if !task.subscribers.contains(&client_id) {
-                    task.subscribers.push(client_id);
-                  }
-                } else {
-                  panic!("Chunk is in a weird state")
-                }
-              } else {
-                //Spawn chunk gen task
-                info!("^ NewGenTask");
-                let blocks_uwu = blocks.clone();
-                let task = pool.spawn(async move {
-                  let chunk = generate_chunk(x, y, &blocks_uwu);
-                  let cumpressed = bincode::serialize(&ServerToClientMessages::ChunkData { 
-                    data: chunk.clone().into(), 
-                    position: (x, y)
-                  }).unwrap();
-                  (chunk, cumpressed)
-                });
-                //Spawn Chunk entity
-                let entity = commands.spawn()
-                  .insert(Chunk)
-                  .insert(ChunkPosition(x, y))
-                  .insert(ChunkGenTask{
-                    task,
-                    subscribers: vec![client_id]
-                  }).id();
-                chunk_map.insert(ChunkPosition(x, y), entity);
-              }
+            ClientToServerMessages::ChunkRequest { x, y } => {
+              chunk_resends.send(ChunkResendRequest { client_id, position: ChunkPosition(x, y) });
+            },
+
+            ClientToServerMessages::SetViewDistance { distance } => {
+              view_distances.send(ViewDistanceRequest { client_id, distance });
             },
 
             ClientToServerMessages::ChatMessage { message } => {
@@ -339,7 +501,7 @@ fn handle_incoming_stuff(
                 bincode::serialize(&ServerToClientMessages::ChatMessage { 
                   message: ChatMessage {
                     message,
-                    from: players.get(*lobby.players.get(&client_id).unwrap()).unwrap().1.0.clone(),
+                    from: players.get(*lobby.players.get(&client_id).unwrap()).unwrap().0.clone(),
                     timestamp: SystemTime::now(),
                     is_system: false
                   }
@@ -347,17 +509,22 @@ fn handle_incoming_stuff(
               );
             },
 
-            ClientToServerMessages::PlayerMove { new_pos } => {
-              if let Some(entity) = lobby.players.get(&client_id) {
-                let transform: &mut Transform = &mut players.get_mut(*entity).unwrap().0;
-                transform.translation = new_pos;
-                server.broadcast_message_except(
-                  client_id, CHANNEL_UNRELIABLE, 
-                  bincode::serialize(&ServerToClientMessages::PlayerSync {
-                    id: client_id, new_pos 
-                  }).unwrap()
-                );
-              }
+            ClientToServerMessages::PlayerMove { new_pos, orientation, sequence } => {
+              move_requests.send(PlayerMoveRequest { client_id, new_pos, orientation, sequence });
+            },
+
+            ClientToServerMessages::BreakBlock { position } => {
+              block_changes.send(BlockChangeRequest {
+                client_id, position,
+                change: BlockChange::Break
+              });
+            },
+
+            ClientToServerMessages::PlaceBlock { position, block } => {
+              block_changes.send(BlockChangeRequest {
+                client_id, position,
+                change: BlockChange::Place(block)
+              });
             },
 
             _ => warn!("Unhandled message type")
@@ -373,15 +540,20 @@ impl Plugin for ServerPlugin {
   fn build(&self, app: &mut App) {
     //Generate private key 
     app.add_event::<SendSysMessageEvt>();
+    app.add_event::<ChunkLoadRequest>();
     app.init_resource::<Lobby>();
     app.init_resource::<ChunkMap>();
+    app.init_resource::<ServerTick>();
     app.insert_resource(PrivateKey(StdRng::from_entropy().gen()));
     app.add_plugin(RenetServerPlugin);
     app.add_startup_system(create_renet_server);
+    app.add_system_to_stage(CoreStage::First, advance_tick);
     app.add_system(print_on_renet_error_system);
     app.add_system(server_update_system);
     app.add_system(handle_incoming_stuff);
+    app.add_system(load_requested_chunks);
     app.add_system(process_chunk_gen_tasks);
+    app.add_system(cancel_abandoned_gen_tasks);
     app.add_system(process_chunk_compress_tasks);
     app.add_system(send_system_messages);
   }
@@ -0,0 +1,400 @@
+use bevy::prelude::*;
+use bevy::{
+  app::AppExit,
+  ecs::query::{WorldQuery, FilterFetch},
+  tasks::{Task, AsyncComputeTaskPool},
+  utils::HashMap,
+};
+use futures_lite::future;
+use tokio::runtime::Runtime as TokioRuntime;
+use std::{
+  fs,
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+  time::Duration,
+};
+use shared::{
+  blocks::{BlockTypeManager, PaletteEntry},
+  types::{
+    block::Block,
+    chunk::{ChunkData, ChunkPosition, ChunkDataComponent, CompressedChunkData},
+  },
+};
+use crate::{
+  Args,
+  server::ChunkMessageCache,
+};
+
+//Region file layout:
+//[magic: 4 bytes][REGION_CHUNKS x (offset: u32 LE, length: u32 LE)][payloads...]
+//Payloads are CompressedChunkData bytes, offset 0 means that the chunk is not stored
+const REGION_SIZE: i64 = 32;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE) as usize;
+const REGION_MAGIC: &[u8; 4] = b"RGN1";
+const HEADER_SIZE: usize = REGION_MAGIC.len() + REGION_CHUNKS * 8;
+//Block palette the state ids in the region files refer to
+const PALETTE_FILE: &str = "palette.json";
+
+//Chunk was generated or modified since the last save
+#[derive(Component, Debug, Clone, Copy)]
+pub struct DirtyChunk;
+
+fn invalid_data(reason: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+//Returns (region position, chunk index inside of the region)
+fn locate(x: i64, y: i64) -> ((i64, i64), usize) {
+  (
+    (x.div_euclid(REGION_SIZE), y.div_euclid(REGION_SIZE)),
+    (x.rem_euclid(REGION_SIZE) * REGION_SIZE + y.rem_euclid(REGION_SIZE)) as usize
+  )
+}
+
+fn read_header(file: &mut fs::File) -> io::Result<Vec<(u32, u32)>> {
+  let mut header = vec![0; HEADER_SIZE];
+  file.read_exact(&mut header)?;
+  if &header[..REGION_MAGIC.len()] != REGION_MAGIC {
+    return Err(invalid_data("Invalid region file magic"));
+  }
+  Ok(header[REGION_MAGIC.len()..].chunks_exact(8).map(|entry| (
+    u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+    u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+  )).collect())
+}
+
+struct Region {
+  chunks: Vec<Option<Vec<u8>>>
+}
+impl Region {
+  fn new() -> Self {
+    Self { chunks: vec![None; REGION_CHUNKS] }
+  }
+
+  fn read(path: &Path) -> io::Result<Self> {
+    let mut file = match fs::File::open(path) {
+      Ok(file) => file,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+      Err(error) => return Err(error)
+    };
+    let header = read_header(&mut file)?;
+    let mut region = Self::new();
+    for (index, (offset, length)) in header.into_iter().enumerate() {
+      if offset == 0 { continue }
+      let mut payload = vec![0; length as usize];
+      file.seek(SeekFrom::Start(offset as u64))?;
+      file.read_exact(&mut payload)?;
+      region.chunks[index] = Some(payload);
+    }
+    Ok(region)
+  }
+
+  //Writes into a temporary file first, so a crash can't leave a half-written region behind
+  fn write(&self, path: &Path) -> io::Result<()> {
+    let mut header = REGION_MAGIC.to_vec();
+    let mut payloads = Vec::new();
+    for chunk in &self.chunks {
+      let (offset, length) = match chunk {
+        Some(payload) => {
+          let entry = ((HEADER_SIZE + payloads.len()) as u32, payload.len() as u32);
+          payloads.extend_from_slice(payload);
+          entry
+        },
+        None => (0, 0)
+      };
+      header.extend_from_slice(&offset.to_le_bytes());
+      header.extend_from_slice(&length.to_le_bytes());
+    }
+    let temp_path = path.with_extension("tmp");
+    {
+      let mut file = fs::File::create(&temp_path)?;
+      file.write_all(&header)?;
+      file.write_all(&payloads)?;
+      file.sync_all()?;
+    }
+    fs::rename(temp_path, path)
+  }
+}
+
+//Maps state ids stored in the world to the ones of the registered blocks and back
+#[derive(Debug)]
+struct StateRemap {
+  to_current: Vec<u16>,
+  to_stored: Vec<u16>,
+  identity: bool,
+}
+impl StateRemap {
+  //Blocks missing from the world palette are appended to it, so state ids that are already stored never change
+  //Worlds without a palette file were saved with the current block palette
+  fn load(path: &Path, blocks: &BlockTypeManager) -> io::Result<(Self, Vec<PaletteEntry>)> {
+    let mut palette: Vec<PaletteEntry> = match fs::read(path) {
+      Ok(data) => serde_json::from_slice(&data).map_err(|error| invalid_data(&error.to_string()))?,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+      Err(error) => return Err(error)
+    };
+    for entry in blocks.palette() {
+      if !palette.iter().any(|stored| stored.key == entry.key) {
+        palette.push(entry);
+      }
+    }
+    //Blocks that were skipped or changed since the world was saved become air,
+    //their entries stay in the palette so they can be restored once they're fixed
+    let mut to_current = Vec::new();
+    let mut to_stored = vec![0; blocks.state_amount()];
+    for entry in &palette {
+      match blocks.get_by_key(&entry.key) {
+        Some(block) if block.state_count() == entry.states as usize => {
+          let base = block.default_state();
+          for state in 0..entry.states as u16 {
+            to_stored[(base + state) as usize] = to_current.len() as u16;
+            to_current.push(base + state);
+          }
+        },
+        _ => {
+          warn!("Block \"{}\" is missing or has different states, it's loaded as air", entry.key);
+          to_current.extend((0..entry.states).map(|_| Block::AIR.state));
+        }
+      }
+    }
+    let identity = to_current.iter().enumerate().all(|(stored, &current)| stored == current as usize);
+    Ok((Self { to_current, to_stored, identity }, palette))
+  }
+}
+
+#[derive(Clone)]
+pub struct WorldStorage {
+  dir: PathBuf,
+  remap: Arc<StateRemap>,
+  //Only one save can run at a time
+  write_lock: Arc<Mutex<()>>,
+}
+impl WorldStorage {
+  pub fn new(dir: PathBuf, blocks: &BlockTypeManager) -> io::Result<Self> {
+    fs::create_dir_all(&dir)?;
+    let palette_path = dir.join(PALETTE_FILE);
+    let (remap, palette) = StateRemap::load(&palette_path, blocks)?;
+    if !remap.identity {
+      info!("Block palette of the world differs from the registered blocks, remapping block states");
+    }
+    let temp_path = palette_path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(&palette)?)?;
+    fs::rename(temp_path, palette_path)?;
+    Ok(Self { dir, remap: Arc::new(remap), write_lock: default() })
+  }
+
+  fn region_path(&self, region: (i64, i64)) -> PathBuf {
+    self.dir.join(format!("r.{}.{}.region", region.0, region.1))
+  }
+
+  pub fn load_chunk(&self, x: i64, y: i64) -> io::Result<Option<ChunkData>> {
+    let (region, index) = locate(x, y);
+    let mut file = match fs::File::open(self.region_path(region)) {
+      Ok(file) => file,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(error) => return Err(error)
+    };
+    let (offset, length) = read_header(&mut file)?[index];
+    if offset == 0 {
+      return Ok(None);
+    }
+    let mut payload = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut payload)?;
+    let mut chunk = ChunkData::try_from(CompressedChunkData(payload))
+      .map_err(|error| invalid_data(&error.to_string()))?;
+    if !self.remap.identity {
+      chunk.remap(&self.remap.to_current);
+    }
+    Ok(Some(chunk))
+  }
+
+  //Copies the region file containing the chunk aside, an existing backup is never overwritten
+  pub fn backup_region(&self, x: i64, y: i64) -> io::Result<PathBuf> {
+    let _lock = self.write_lock.lock().unwrap();
+    let path = self.region_path(locate(x, y).0);
+    let backup = path.with_extension("region.bak");
+    if !backup.exists() {
+      fs::copy(&path, &backup)?;
+    }
+    Ok(backup)
+  }
+
+  //Compresses and saves the chunks
+  pub fn save_chunks(&self, chunks: Vec<(ChunkPosition, ChunkData)>) -> io::Result<()> {
+    let mut regions: HashMap<(i64, i64), Vec<(usize, CompressedChunkData)>> = HashMap::default();
+    for (position, mut data) in chunks {
+      if !self.remap.identity {
+        data.remap(&self.remap.to_stored);
+      }
+      let (region, index) = locate(position.0, position.1);
+      regions.entry(region).or_default().push((index, (&data).into()));
+    }
+    let _lock = self.write_lock.lock().unwrap();
+    for (region_position, chunks) in regions {
+      let path = self.region_path(region_position);
+      let mut region = Region::read(&path)?;
+      for (index, data) in chunks {
+        region.chunks[index] = Some(data.0);
+      }
+      region.write(&path)?;
+    }
+    Ok(())
+  }
+}
+
+pub struct AutosaveTimer(pub Timer);
+
+//A save of the chunk is running, it stays dirty until the save succeeds
+#[derive(Component, Debug, Clone, Copy)]
+pub struct SavingChunk;
+
+//Chunk entity, its position, the data to save and the ChunkMessageCache revision of that data
+pub type SaveEntry = (Entity, ChunkPosition, ChunkData, u32);
+
+struct PendingSave {
+  task: Task<io::Result<()>>,
+  chunks: Vec<(Entity, u32)>,
+}
+
+#[derive(Default)]
+pub struct PendingSaves(Vec<PendingSave>);
+impl PendingSaves {
+  //Compresses and saves the chunks on the task pool
+  pub fn save(&mut self, commands: &mut Commands, pool: &AsyncComputeTaskPool, storage: &WorldStorage, chunks: Vec<SaveEntry>) {
+    let mut saved = Vec::with_capacity(chunks.len());
+    let mut data = Vec::with_capacity(chunks.len());
+    for (entity, position, chunk, revision) in chunks {
+      commands.entity(entity).insert(SavingChunk);
+      saved.push((entity, revision));
+      data.push((position, chunk));
+    }
+    let storage = storage.clone();
+    self.0.push(PendingSave {
+      task: pool.spawn(async move { storage.save_chunks(data) }),
+      chunks: saved,
+    });
+  }
+}
+
+//Set from the Ctrl+C handler thread
+#[derive(Default, Clone)]
+struct ShutdownFlag(Arc<AtomicBool>);
+
+type DirtyChunks<'w, 's, F> = Query<'w, 's, (Entity, &'static ChunkPosition, &'static ChunkDataComponent, &'static ChunkMessageCache), F>;
+
+fn collect_dirty_chunks<F: WorldQuery>(chunks: &DirtyChunks<F>) -> Vec<SaveEntry> where F::Fetch: FilterFetch {
+  chunks.iter().map(|(entity, position, data, cache)| {
+    (entity, *position, data.0.clone(), cache.revision())
+  }).collect()
+}
+
+fn setup_storage(
+  mut commands: Commands,
+  args: Res<Args>,
+  blocks: Res<BlockTypeManager>,
+  flag: Res<ShutdownFlag>,
+) {
+  let storage = WorldStorage::new(args.world.clone(), &blocks).expect("Failed to open the world directory");
+  info!("World directory: {:?}", &args.world);
+  commands.insert_resource(storage);
+  commands.insert_resource(AutosaveTimer(Timer::new(Duration::from_secs(args.autosave), true)));
+
+  //Save the world on Ctrl+C instead of dying instantly
+  let flag = flag.0.clone();
+  std::thread::spawn(move || {
+    let runtime = TokioRuntime::new().unwrap();
+    runtime.block_on(tokio::signal::ctrl_c()).expect("Failed to listen for Ctrl+C");
+    flag.store(true, Ordering::SeqCst);
+  });
+}
+
+//Chunks are only marked clean once they're on disk and haven't changed since
+fn finish_saves(
+  mut commands: Commands,
+  mut pending: ResMut<PendingSaves>,
+  caches: Query<&ChunkMessageCache>,
+) {
+  let mut finished = Vec::new();
+  pending.0.retain_mut(|save| match future::block_on(future::poll_once(&mut save.task)) {
+    Some(result) => {
+      finished.push((result, std::mem::take(&mut save.chunks)));
+      false
+    },
+    None => true
+  });
+  for (result, chunks) in finished {
+    if let Err(error) = &result {
+      error!("Failed to save {} chunks, they are kept loaded until they're saved: {}", chunks.len(), error);
+    }
+    for (entity, revision) in chunks {
+      let cache = match caches.get(entity) {
+        Ok(cache) => cache,
+        Err(_) => continue
+      };
+      let mut ecmd = commands.entity(entity);
+      ecmd.remove::<SavingChunk>();
+      if result.is_ok() && cache.revision() == revision {
+        ecmd.remove::<DirtyChunk>();
+      }
+    }
+  }
+}
+
+fn autosave_system(
+  mut commands: Commands,
+  time: Res<Time>,
+  mut timer: ResMut<AutosaveTimer>,
+  mut pending: ResMut<PendingSaves>,
+  storage: Res<WorldStorage>,
+  pool: Res<AsyncComputeTaskPool>,
+  chunks: DirtyChunks<(With<DirtyChunk>, Without<SavingChunk>)>,
+) {
+  if !timer.0.tick(time.delta()).just_finished() {
+    return;
+  }
+  let dirty = collect_dirty_chunks(&chunks);
+  if dirty.is_empty() {
+    return;
+  }
+  info!("Autosaving {} chunks", dirty.len());
+  pending.save(&mut commands, &pool, &storage, dirty);
+}
+
+fn save_on_shutdown(
+  flag: Res<ShutdownFlag>,
+  mut pending: ResMut<PendingSaves>,
+  storage: Res<WorldStorage>,
+  chunks: DirtyChunks<With<DirtyChunk>>,
+  mut exit: EventWriter<AppExit>,
+) {
+  if !flag.0.load(Ordering::SeqCst) {
+    return;
+  }
+  info!("Shutting down...");
+  //Wait for saves that are still running, then save everything that's still dirty again
+  for save in pending.0.drain(..) {
+    let _ = future::block_on(save.task);
+  }
+  let dirty: Vec<_> = collect_dirty_chunks(&chunks).into_iter()
+    .map(|(_, position, data, _)| (position, data))
+    .collect();
+  info!("Saving {} chunks", dirty.len());
+  if let Err(error) = storage.save_chunks(dirty) {
+    error!("Failed to save the world: {}", error);
+  }
+  flag.0.store(false, Ordering::SeqCst);
+  exit.send(AppExit);
+}
+
+pub struct WorldStoragePlugin;
+impl Plugin for WorldStoragePlugin {
+  fn build(&self, app: &mut App) {
+    app.init_resource::<PendingSaves>();
+    app.init_resource::<ShutdownFlag>();
+    app.add_startup_system(setup_storage);
+    app.add_system(finish_saves);
+    app.add_system(autosave_system);
+    app.add_system(save_on_shutdown);
+  }
+}
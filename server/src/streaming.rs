@@ -0,0 +1,244 @@
+use bevy::prelude::*;
+use bevy::utils::{HashSet, HashMap};
+use bevy_renet::renet::RenetServer;
+use std::collections::VecDeque;
+use shared::{
+  messages::ServerToClientMessages,
+  consts::{CHANNEL_RELIABLE, CHANNEL_BLOCK, CHUNK_SIZE, DEFAULT_CLIENT_VIEW_DIST, MAX_MP_VIEW_DIST, MAX_MP_REQ_DIST},
+  types::{
+    chunk::{ChunkPosition, ChunkMap},
+    net::Lobby,
+  },
+};
+use crate::server::{Player, ChunkSubscribers, ChunkLoadRequest, ChunkGenTask, ChunkCompressTask};
+
+//Max amount of new chunks each client gets subscribed to per update
+const CHUNKS_PER_TICK: usize = 4;
+//No new chunks are queued for a client while it's waiting for this many chunks to be generated or sent
+const MAX_PENDING_CHUNKS: usize = 16;
+//Chunk resend requests a client can make per second, and how many of them it can save up
+const RESENDS_PER_SECOND: f32 = 4.;
+const MAX_RESEND_BURST: f32 = 16.;
+
+//Token bucket for chunk resend requests
+#[derive(Debug)]
+struct RequestBudget {
+  tokens: f32,
+  last_update: f64,
+}
+impl Default for RequestBudget {
+  fn default() -> Self {
+    Self { tokens: MAX_RESEND_BURST, last_update: 0. }
+  }
+}
+impl RequestBudget {
+  fn try_take(&mut self, now: f64) -> bool {
+    let elapsed = (now - self.last_update) as f32;
+    self.tokens = (self.tokens + elapsed * RESENDS_PER_SECOND).min(MAX_RESEND_BURST);
+    self.last_update = now;
+    if self.tokens < 1. {
+      return false;
+    }
+    self.tokens -= 1.;
+    true
+  }
+}
+
+//Sent by handle_incoming_stuff
+#[derive(Clone, Copy, Debug)]
+pub struct ViewDistanceRequest {
+  pub client_id: u64,
+  pub distance: usize,
+}
+
+//Sent by handle_incoming_stuff, the client wants a chunk it already holds to be sent again
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkResendRequest {
+  pub client_id: u64,
+  pub position: ChunkPosition,
+}
+
+//Chunks streamed to a single client
+#[derive(Component, Debug)]
+pub struct ClientChunks {
+  pub view_distance: usize,
+  //Chunk the player was in when the queue was last rebuilt
+  center: Option<ChunkPosition>,
+  //Chunks the client is subscribed to, including ones that are still being generated
+  pub loaded: HashSet<ChunkPosition>,
+  //Chunks in view distance that haven't been sent yet, nearest first
+  queue: VecDeque<ChunkPosition>,
+  resend_budget: RequestBudget,
+}
+impl Default for ClientChunks {
+  fn default() -> Self {
+    Self {
+      view_distance: DEFAULT_CLIENT_VIEW_DIST,
+      center: None,
+      loaded: HashSet::default(),
+      queue: VecDeque::new(),
+      resend_budget: RequestBudget::default(),
+    }
+  }
+}
+impl ClientChunks {
+  #[inline] fn in_view(&self, position: ChunkPosition, center: ChunkPosition) -> bool {
+    chunk_distance(position, center) <= self.view_distance
+  }
+}
+
+fn chunk_distance(a: ChunkPosition, b: ChunkPosition) -> usize {
+  (a.0 - b.0).abs().max((a.1 - b.1).abs()) as _
+}
+
+fn player_chunk(translation: Vec3) -> ChunkPosition {
+  ChunkPosition(
+    (translation.x / CHUNK_SIZE as f32).floor() as i64,
+    (translation.z / CHUNK_SIZE as f32).floor() as i64,
+  )
+}
+
+fn set_view_distance(
+  mut requests: EventReader<ViewDistanceRequest>,
+  mut server: ResMut<RenetServer>,
+  lobby: Res<Lobby>,
+  mut players: Query<&mut ClientChunks, With<Player>>,
+) {
+  for request in requests.iter() {
+    let entity = match lobby.players.get(&request.client_id) {
+      Some(&entity) => entity,
+      None => continue
+    };
+    let mut chunks = match players.get_mut(entity) {
+      Ok(chunks) => chunks,
+      Err(_) => continue
+    };
+    let distance = request.distance.min(MAX_MP_VIEW_DIST);
+    info!("Client {} view distance: {} (requested {})", request.client_id, distance, request.distance);
+    chunks.view_distance = distance;
+    //Forces the queue to be rebuilt
+    chunks.center = None;
+    server.send_message(
+      request.client_id, CHANNEL_RELIABLE,
+      bincode::serialize(&ServerToClientMessages::ViewDistance { distance }).unwrap()
+    );
+  }
+}
+
+fn resend_chunks(
+  mut requests: EventReader<ChunkResendRequest>,
+  mut load_requests: EventWriter<ChunkLoadRequest>,
+  time: Res<Time>,
+  lobby: Res<Lobby>,
+  mut players: Query<(&Transform, &mut ClientChunks), With<Player>>,
+) {
+  let now = time.seconds_since_startup();
+  for request in requests.iter() {
+    let entity = match lobby.players.get(&request.client_id) {
+      Some(&entity) => entity,
+      None => continue
+    };
+    let (transform, mut chunks) = match players.get_mut(entity) {
+      Ok(player) => player,
+      Err(_) => continue
+    };
+    let rejection = if chunk_distance(request.position, player_chunk(transform.translation)) > MAX_MP_REQ_DIST {
+      Some("too far away from the player")
+    } else if !chunks.loaded.contains(&request.position) {
+      Some("not subscribed to it")
+    } else if !chunks.resend_budget.try_take(now) {
+      Some("out of request budget")
+    } else {
+      None
+    };
+    match rejection {
+      Some(reason) => warn!("Rejected request for chunk {:?} from client {}: {}", request.position, request.client_id, reason),
+      None => load_requests.send(ChunkLoadRequest { client_id: request.client_id, position: request.position }),
+    }
+  }
+}
+
+fn stream_chunks(
+  mut server: ResMut<RenetServer>,
+  mut load_requests: EventWriter<ChunkLoadRequest>,
+  chunk_map: Res<ChunkMap>,
+  mut subscribers: Query<(&mut ChunkSubscribers, Option<&ChunkGenTask>)>,
+  compress_tasks: Query<&ChunkCompressTask>,
+  mut players: Query<(&Player, &Transform, &mut ClientChunks)>,
+) {
+  //Chunks each client is still waiting for
+  let mut pending: HashMap<u64, usize> = HashMap::default();
+  for (chunk_subscribers, _) in subscribers.iter().filter(|(_, task)| task.is_some()) {
+    for &client_id in chunk_subscribers.0.iter() {
+      *pending.entry(client_id).or_default() += 1;
+    }
+  }
+  for task in compress_tasks.iter() {
+    for &client_id in task.clients.iter() {
+      *pending.entry(client_id).or_default() += 1;
+    }
+  }
+
+  for (player, transform, mut chunks) in players.iter_mut() {
+    let center = player_chunk(transform.translation);
+    if chunks.center != Some(center) {
+      chunks.center = Some(center);
+
+      //Unload chunks that are out of view distance
+      let out_of_view: Vec<ChunkPosition> = chunks.loaded.iter()
+        .copied()
+        .filter(|&position| !chunks.in_view(position, center))
+        .collect();
+      for position in out_of_view {
+        chunks.loaded.remove(&position);
+        if let Some(entity) = chunk_map.get(position) {
+          if let Ok((mut subscribers, _)) = subscribers.get_mut(entity) {
+            subscribers.0.remove(&player.id);
+          }
+        }
+        server.send_message(
+          player.id, CHANNEL_BLOCK,
+          bincode::serialize(&ServerToClientMessages::UnloadChunk {
+            position: (position.0, position.1)
+          }).unwrap()
+        );
+      }
+
+      //Queue chunks that are not loaded yet, nearest first
+      let range = -(chunks.view_distance as i64)..=(chunks.view_distance as i64);
+      let mut queue: Vec<ChunkPosition> = range.clone()
+        .flat_map(|x| range.clone().map(move |y| ChunkPosition(center.0 + x, center.1 + y)))
+        .filter(|position| !chunks.loaded.contains(position))
+        .collect();
+      queue.sort_by_key(|position| {
+        let (x, y) = (position.0 - center.0, position.1 - center.1);
+        x * x + y * y
+      });
+      chunks.queue = queue.into();
+    }
+
+    let mut sent = 0;
+    let waiting = pending.get(&player.id).copied().unwrap_or(0);
+    while sent < CHUNKS_PER_TICK && waiting + sent < MAX_PENDING_CHUNKS {
+      let position = match chunks.queue.pop_front() {
+        Some(position) => position,
+        None => break
+      };
+      if chunks.loaded.insert(position) {
+        load_requests.send(ChunkLoadRequest { client_id: player.id, position });
+        sent += 1;
+      }
+    }
+  }
+}
+
+pub struct StreamingPlugin;
+impl Plugin for StreamingPlugin {
+  fn build(&self, app: &mut App) {
+    app.add_event::<ViewDistanceRequest>();
+    app.add_event::<ChunkResendRequest>();
+    app.add_system(set_view_distance);
+    app.add_system(resend_chunks);
+    app.add_system(stream_chunks);
+  }
+}
@@ -30,24 +30,28 @@ const IRON_ORE_AMOUNT: f64      = 0.1;
 const GOLD_ORE_AMOUNT: f64      = 0.075;
 const EMERALD_ORE_AMOUNT: f64   = 0.025;
 
+const FLOWER_AMOUNT: f64        = 0.02;//Chance of a flower on each grass block
+
 //========================================
 
 const TERRAIN_HEIGHT_HALF: f64 = TERRAIN_HEIGHT / 2.;
 
 pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
-  let index_of = |key| blocks.get_by_key(key).unwrap().index.unwrap() as u16;
+  let state_of = |key| blocks.get_by_key(key).unwrap().default_state();
+
+  let air_index     = state_of("air");
+  let dirt_index    = state_of("dirt");
+  let grass_index   = state_of("grass");
+  let stone_index   = state_of("stone");
+  let bedrock_index = state_of("bedrock");
 
-  let air_index     = index_of("air");
-  let dirt_index    = index_of("dirt");
-  let grass_index   = index_of("grass");
-  let stone_index   = index_of("stone");
-  let bedrock_index = index_of("bedrock");
+  let diamond_ore_index = state_of("diamond_ore");
+  let coal_ore_index    = state_of("coal_ore");
+  let emerald_ore_index = state_of("emerald_ore");
+  let gold_ore_index    = state_of("gold_ore");
+  let iron_ore_index    = state_of("iron_ore");
 
-  let diamond_ore_index = index_of("diamond_ore");
-  let coal_ore_index    = index_of("coal_ore");
-  let emerald_ore_index = index_of("emerald_ore");
-  let gold_ore_index    = index_of("gold_ore");
-  let iron_ore_index    = index_of("iron_ore");
+  let poppy_index = state_of("poppy");
 
   let ore_amounts = [
     (coal_ore_index, COAL_ORE_AMOUNT),
@@ -63,7 +67,6 @@ pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
 
   //Create ChunkData
   let mut data = ChunkData::new();
-  let blocks = &mut data.0;
 
   //Create FBM (Fractional Brownian Motion) noise generator
   //fbm.get() return data in range -1..=1
@@ -79,12 +82,14 @@ pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
 
   //Create RNG
   let mut rng = SmallRng::seed_from_u64(PRNG_SEED ^ (x as u64) ^ (y as u64));
+  //Separate RNG for decorations, so adding them doesn't change the terrain
+  let mut decoration_rng = SmallRng::seed_from_u64(!PRNG_SEED ^ (x as u64) ^ (y as u64));
 
   for x in 0..CHUNK_SIZE {
     for z in 0..CHUNK_SIZE {
       //Fill with air
       for y in 0..CHUNK_HEIGHT {
-        blocks[x][y][z] = Block { block_type: air_index };
+        data.set(x, y, z, Block { state: air_index });
       }
 
       //Get terrain height
@@ -98,15 +103,15 @@ pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
             (1. - ((y - MIN_TERRAIN_HEIGHT) as f64 / (TERRAIN_HEIGHT * TERRAIN_STONE_START))).min(1.).max(0.)
           } else { 1. }
         };
-        blocks[x][y][z] = Block { 
-          block_type: if rng.gen_bool(stone_probability) { 
+        data.set(x, y, z, Block { 
+          state: if rng.gen_bool(stone_probability) { 
             stone_index 
           } else if y == (h - 1) { 
             grass_index 
           } else {
             dirt_index
           }
-        }; 
+        }); 
       }
 
       //Generate caves
@@ -121,21 +126,21 @@ pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
           };
           let is_cave = (cave_fbm.get(point_3d).abs() > treshold) && (cave_fbm.get(point_3d_alt).abs() > treshold);
           if is_cave {
-            blocks[x][y][z] = Block { block_type: air_index };
+            data.set(x, y, z, Block { state: air_index });
           }
         }
       }
 
       //Generate ores
       for y in 0..h {
-        if blocks[x][y][z].block_type != stone_index {
+        if data.get(x, y, z).state != stone_index {
           continue;
         }
         for (i, (ore_index, amount)) in ore_amounts.iter().enumerate() {
           let point_3d = [x_offset + x as f64, (CHUNK_HEIGHT * i) as f64 + y as f64, y_offset + z as f64].map(|x| x * ORE_NOISE_SCALE);
           let val = ore_fbm.get(point_3d);
           if ((val + 1.) / 2.) > (1. - *amount) {
-            blocks[x][y][z] = Block { block_type: *ore_index };
+            data.set(x, y, z, Block { state: *ore_index });
           }
         }
       }
@@ -145,11 +150,16 @@ pub fn generate(x: i64, y: i64, blocks: &BlockTypeManager) -> ChunkData {
         let mut probability = 1.;
         for y in 0..MAX_BEDROCK_HEIGHT {
           if rng.gen_bool(probability) {
-            blocks[x][y][z] = Block { block_type: bedrock_index };
+            data.set(x, y, z, Block { state: bedrock_index });
           }
           probability /= 2.;
         }
       }
+
+      //Add flowers
+      if data.get(x, h - 1, z).state == grass_index && decoration_rng.gen_bool(FLOWER_AMOUNT) {
+        data.set(x, h, z, Block { state: poppy_index });
+      }
     }
   }
 
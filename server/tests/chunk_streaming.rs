@@ -0,0 +1,154 @@
+//Streams chunks from a RenetServer to a RenetClient through a UDP proxy that drops packets,
+//every chunk has to arrive intact and in the order it was sent
+use bevy_renet::renet::{
+  RenetServer, RenetClient, ServerConfig, ConnectToken, NETCODE_KEY_BYTES,
+};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use std::{
+  io,
+  net::{SocketAddr, UdpSocket},
+  thread,
+  time::{Duration, Instant, SystemTime},
+};
+use shared::{
+  consts::{CHANNEL_BLOCK, CHUNK_SIZE, CHUNK_HEIGHT, PROTOCOL_ID, renet_connection_config},
+  messages::ServerToClientMessages,
+  types::{block::Block, chunk::ChunkData},
+};
+
+const CHUNK_COUNT: i64 = 64;
+const DROP_CHANCE: f64 = 0.2;
+const TIMEOUT: Duration = Duration::from_secs(60);
+const CLIENT_ID: u64 = 1;
+
+//Forwards packets between the client and the server, dropping some of them
+struct LossyProxy {
+  socket: UdpSocket,
+  server: SocketAddr,
+  client: Option<SocketAddr>,
+  rng: StdRng,
+}
+impl LossyProxy {
+  fn new(server: SocketAddr) -> Self {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    Self { socket, server, client: None, rng: StdRng::seed_from_u64(0) }
+  }
+
+  fn addr(&self) -> SocketAddr {
+    self.socket.local_addr().unwrap()
+  }
+
+  fn pump(&mut self) {
+    let mut buffer = [0; 2048];
+    loop {
+      let (length, from) = match self.socket.recv_from(&mut buffer) {
+        Ok(received) => received,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+        Err(error) => panic!("Proxy failed to receive: {}", error)
+      };
+      let to = if from == self.server {
+        match self.client {
+          Some(client) => client,
+          None => continue
+        }
+      } else {
+        self.client = Some(from);
+        self.server
+      };
+      if self.rng.gen_bool(DROP_CHANCE) {
+        continue;
+      }
+      self.socket.send_to(&buffer[..length], to).unwrap();
+    }
+  }
+}
+
+//Every chunk is different, so swapped or corrupted chunks can't go unnoticed
+fn test_chunk(index: i64) -> ChunkData {
+  let mut chunk = ChunkData::new();
+  let mut rng = StdRng::seed_from_u64(index as u64);
+  for x in 0..CHUNK_SIZE {
+    for z in 0..CHUNK_SIZE {
+      let height = rng.gen_range(1..CHUNK_HEIGHT / 2);
+      for y in 0..height {
+        chunk.set(x, y, z, Block { state: rng.gen_range(1..64) });
+      }
+    }
+  }
+  chunk
+}
+
+fn assert_same_chunk(expected: &ChunkData, actual: &ChunkData, index: i64) {
+  for x in 0..CHUNK_SIZE {
+    for y in 0..CHUNK_HEIGHT {
+      for z in 0..CHUNK_SIZE {
+        assert_eq!(expected.get(x, y, z), actual.get(x, y, z), "Chunk {} differs at {} {} {}", index, x, y, z);
+      }
+    }
+  }
+}
+
+fn now() -> Duration {
+  SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap()
+}
+
+#[test]
+fn chunks_survive_packet_loss() {
+  let private_key = [7; NETCODE_KEY_BYTES];
+  let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let mut proxy = LossyProxy::new(server_socket.local_addr().unwrap());
+
+  //The client only knows the proxy, so the server has to advertise its address
+  let server_config = ServerConfig::new(1, PROTOCOL_ID, proxy.addr(), private_key);
+  let mut server = RenetServer::new(now(), server_config, renet_connection_config(), server_socket).unwrap();
+  let token = ConnectToken::generate(
+    now(), PROTOCOL_ID, 300, CLIENT_ID, 15, vec![proxy.addr()], None, &private_key
+  ).unwrap();
+  let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+  let mut client = RenetClient::new(now(), client_socket, CLIENT_ID, token, renet_connection_config()).unwrap();
+
+  let chunks: Vec<ChunkData> = (0..CHUNK_COUNT).map(test_chunk).collect();
+  let mut sent = false;
+  let mut received = 0;
+  let start = Instant::now();
+  let mut last_update = Instant::now();
+
+  while received < CHUNK_COUNT {
+    assert!(start.elapsed() < TIMEOUT, "Only {} of {} chunks arrived in time", received, CHUNK_COUNT);
+    assert!(client.disconnected().is_none(), "Client disconnected: {:?}", client.disconnected());
+
+    let delta = last_update.elapsed();
+    last_update = Instant::now();
+    server.update(delta).unwrap();
+    client.update(delta).unwrap();
+
+    if client.is_connected() && !sent {
+      for (index, chunk) in chunks.iter().enumerate() {
+        let message = bincode::serialize(&ServerToClientMessages::ChunkData {
+          data: chunk.into(),
+          position: (index as i64, 0),
+        }).unwrap();
+        server.send_message(CLIENT_ID, CHANNEL_BLOCK, message);
+      }
+      sent = true;
+    }
+
+    while let Some(message) = client.receive_message(CHANNEL_BLOCK) {
+      match bincode::deserialize(&message).unwrap() {
+        ServerToClientMessages::ChunkData { data, position } => {
+          assert_eq!(position, (received, 0), "Chunks arrived out of order");
+          let chunk = ChunkData::try_from(data).expect("Failed to decode chunk");
+          assert_same_chunk(&chunks[received as usize], &chunk, received);
+          received += 1;
+        },
+        _ => panic!("Unexpected message")
+      }
+    }
+
+    server.send_packets().unwrap();
+    client.send_packets().unwrap();
+    proxy.pump();
+    thread::sleep(Duration::from_millis(1));
+  }
+}
@@ -0,0 +1,160 @@
+use bevy::log::{info, error};
+use serde::Deserialize;
+use std::{
+  collections::HashMap,
+  fmt, fs, io,
+  path::{Path, PathBuf},
+};
+use crate::types::CubeFace;
+use super::{BlockMetadata, BlockTypeManager, BlockFlags, BlockShape, BlockProperty, RegisterError};
+
+const DEFINITION_EXTENSION: &str = "json";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDefinition {
+  pub key: String,
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub textures: Vec<String>,
+  #[serde(default)]
+  pub face_textures: HashMap<CubeFace, usize>,
+  #[serde(default)]
+  pub optimize_sides: Option<[bool; 6]>,
+  #[serde(default)]
+  pub flags: Option<Vec<BlockFlags>>,
+  #[serde(default)]
+  pub shape: BlockShape,
+  #[serde(default)]
+  pub light_emission: u8,
+  #[serde(default)]
+  pub properties: Vec<BlockProperty>,
+}
+impl From<BlockDefinition> for BlockMetadata {
+  fn from(def: BlockDefinition) -> Self {
+    let default = BlockMetadata::default();
+    let mut face_textures = default.face_textures;
+    for (face, index) in def.face_textures {
+      face_textures[face as usize] = index;
+    }
+    Self {
+      index: None,
+      name: def.name.unwrap_or(default.name),
+      textures: def.textures.into_iter().map(|x| x.into()).collect(),
+      face_textures,
+      optimize_sides: def.optimize_sides.unwrap_or(default.optimize_sides),
+      flags: match def.flags {
+        Some(flags) => flags.iter().fold(0, |acc, &flag| acc | flag as u16),
+        None => default.flags
+      },
+      shape: def.shape,
+      light_emission: def.light_emission,
+      properties: def.properties,
+      state_base: None,
+      key: def.key,
+    }
+  }
+}
+
+//A definition file contains either a single block or a list of blocks
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DefinitionFile {
+  Single(BlockDefinition),
+  Multiple(Vec<BlockDefinition>),
+}
+
+#[derive(Debug)]
+pub enum BlockLoadError {
+  Io { path: PathBuf, error: io::Error },
+  Parse { path: PathBuf, error: serde_json::Error },
+  Invalid { path: PathBuf, key: String, error: RegisterError },
+}
+impl fmt::Display for BlockLoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+      Self::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+      Self::Invalid { path, key, error } => write!(f, "{}: block \"{}\": {}", path.display(), key, error),
+    }
+  }
+}
+impl std::error::Error for BlockLoadError {}
+
+//Every chunk is full of air, nothing can be loaded or generated without it
+#[derive(Debug)]
+pub struct MissingAirError(pub PathBuf);
+impl fmt::Display for MissingAirError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "No air block defined in {}", self.0.display())
+  }
+}
+impl std::error::Error for MissingAirError {}
+
+fn read_definition_file(path: &Path) -> Result<Vec<BlockDefinition>, BlockLoadError> {
+  let contents = fs::read_to_string(path).map_err(|error| {
+    BlockLoadError::Io { path: path.into(), error }
+  })?;
+  let parsed = serde_json::from_str(&contents).map_err(|error| {
+    BlockLoadError::Parse { path: path.into(), error }
+  })?;
+  Ok(match parsed {
+    DefinitionFile::Single(def) => vec![def],
+    DefinitionFile::Multiple(defs) => defs,
+  })
+}
+
+impl BlockTypeManager {
+  //Loads the block definitions in `dir`, broken files and invalid blocks are logged and skipped
+  //States of skipped blocks become air when chunks are loaded, so they don't corrupt anything
+  pub fn load(dir: &Path) -> Result<Self, MissingAirError> {
+    info!("Loading block definitions from {:?}", dir);
+    let mut blocks = Self::default();
+    for error in blocks.load_dir(dir) {
+      error!("Failed to load block definition: {}", error);
+    }
+    if !blocks.get_by_state(0).map_or(false, |block| block.is_air()) {
+      return Err(MissingAirError(dir.into()));
+    }
+    info!("Registered {} block types with {} states", blocks.amount(), blocks.state_amount());
+    Ok(blocks)
+  }
+
+  //Registers every block defined in the json files inside `dir`
+  //Files are loaded in alphabetical order, so block indices stay stable between runs
+  //Broken files and invalid blocks are skipped and returned as errors
+  pub fn load_dir(&mut self, dir: &Path) -> Vec<BlockLoadError> {
+    let mut errors = Vec::new();
+
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+      Ok(entries) => entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == DEFINITION_EXTENSION))
+        .collect(),
+      Err(error) => {
+        errors.push(BlockLoadError::Io { path: dir.into(), error });
+        return errors;
+      }
+    };
+    paths.sort();
+
+    for path in paths {
+      let definitions = match read_definition_file(&path) {
+        Ok(definitions) => definitions,
+        Err(error) => {
+          errors.push(error);
+          continue;
+        }
+      };
+      for definition in definitions {
+        let key = definition.key.clone();
+        if let Err(error) = self.try_register(definition.into()) {
+          errors.push(BlockLoadError::Invalid { path: path.clone(), key, error });
+        }
+      }
+    }
+
+    errors
+  }
+}
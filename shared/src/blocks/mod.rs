@@ -0,0 +1,346 @@
+use bevy::utils::{HashMap, HashSet};
+use path_clean::clean as path_clean;
+use serde::{Serialize, Deserialize};
+use std::{env, fmt, path::{Path, PathBuf}};
+use crate::{types::CubeFace, consts::MAX_LIGHT_LEVEL};
+
+mod definition;
+mod state;
+pub use definition::{BlockDefinition, BlockLoadError, MissingAirError};
+pub use state::{BlockProperty, PropertyKind, PropertyValue, Facing};
+
+//State ids are stored as u16
+const MAX_STATES: usize = u16::MAX as usize + 1;
+
+const INVALID_KEY: &str = "__invalid_key__";
+const BLOCKS_DIR_NAME: &str = "blocks";
+//Used when running from the repository, no matter what the working directory is
+const SOURCE_BLOCKS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/blocks");
+
+//Looks for the block definitions next to the executable first, then in the working directory,
+//then falls back to the ones in the source tree
+pub fn default_blocks_dir() -> PathBuf {
+  let exe_dir = env::current_exe().ok()
+    .and_then(|exe| exe.parent().map(Path::to_path_buf));
+  exe_dir.map(|dir| dir.join(BLOCKS_DIR_NAME))
+    .into_iter()
+    .chain([PathBuf::from(BLOCKS_DIR_NAME)])
+    .find(|dir| dir.is_dir())
+    .unwrap_or_else(|| SOURCE_BLOCKS_DIR.into())
+}
+
+#[repr(u16)]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum BlockFlags {
+  #[serde(rename = "air")]         FlagAir         = 1 << 0,
+  #[serde(rename = "solid")]       FlagSolid       = 1 << 1,
+  #[serde(rename = "liquid")]      FlagLiquid      = 1 << 2,
+  #[serde(rename = "transparent")] FlagTransparent = 1 << 3,
+  #[serde(rename = "unbreakable")] FlagUnbreakable = 1 << 4,
+}
+
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockShape {
+  None,
+  Cube,
+  Cross
+}
+impl Default for BlockShape {
+  fn default() -> Self { Self::Cube }
+}
+
+#[derive(Clone, Debug)]
+pub struct TexturePath(String);
+impl TexturePath {
+  pub fn partial(&self) -> &String {
+    &self.0
+  }
+  pub fn full(&self) -> String {
+    format!("textures/{}.png", path_clean(&self.0))
+  }
+}
+impl From<String> for TexturePath {
+  fn from(string: String) -> Self {
+    Self(string)
+  }    
+}
+impl From<&str> for TexturePath {
+  fn from(string: &str) -> Self {
+    Self(string.into())
+  }    
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockMetadata {
+  pub index: Option<usize>,
+  pub key: String,
+  pub name: String,
+  pub textures: Vec<TexturePath>,
+  pub face_textures: [usize; 6],
+  pub optimize_sides: [bool; 6],
+  pub flags: u16,
+  pub shape: BlockShape,
+  pub light_emission: u8,
+  pub properties: Vec<BlockProperty>,
+  pub state_base: Option<u16>,
+}
+impl Default for BlockMetadata {
+  fn default() -> Self {
+    Self {
+      index: None,
+      key: INVALID_KEY.into(),
+      name: "block".into(),
+      textures: Vec::new(),
+      face_textures: [0; 6],
+      optimize_sides: [true; 6],
+      flags: BlockFlags::FlagSolid as u16,
+      shape: BlockShape::Cube,
+      light_emission: 0,
+      properties: Vec::new(),
+      state_base: None,
+    }
+  }
+}
+impl BlockMetadata {
+  pub fn is_air(&self) -> bool {
+    return (self.flags & BlockFlags::FlagAir as u16) > 0;
+  }
+  pub fn is_solid(&self) -> bool {
+    return (self.flags & BlockFlags::FlagSolid as u16) > 0;
+  }
+  pub fn is_liquid(&self) -> bool {
+    return (self.flags & BlockFlags::FlagLiquid as u16) > 0;
+  }
+  pub fn is_transparent(&self) -> bool {
+    return (self.flags & BlockFlags::FlagTransparent as u16) > 0;
+  }
+  pub fn is_unbreakable(&self) -> bool {
+    return (self.flags & BlockFlags::FlagUnbreakable as u16) > 0;
+  }
+  //Opaque blocks stop light and hide faces of their neighbours
+  pub fn is_opaque(&self) -> bool {
+    self.shape == BlockShape::Cube && !self.is_transparent()
+  }
+
+  //Amount of states, which is the product of value counts of all properties
+  pub fn state_count(&self) -> usize {
+    self.properties.iter().map(|property| property.kind.value_count()).product()
+  }
+  pub fn default_state(&self) -> u16 {
+    self.state_base.expect("Block is not registered")
+  }
+  pub fn has_state(&self, state: u16) -> bool {
+    self.state_base.map_or(false, |base| {
+      (base as usize..base as usize + self.state_count()).contains(&(state as usize))
+    })
+  }
+
+  //Returns (position, stride, offset) of property `name` in `state`
+  fn locate_property(&self, state: u16, name: &str) -> Option<(usize, usize, usize)> {
+    if !self.has_state(state) { return None }
+    let position = self.properties.iter().position(|property| property.name == name)?;
+    let stride: usize = self.properties[(position + 1)..].iter().map(|property| property.kind.value_count()).product();
+    Some((position, stride, (state - self.state_base?) as usize))
+  }
+
+  pub fn get_property(&self, state: u16, name: &str) -> Option<PropertyValue> {
+    let (position, stride, offset) = self.locate_property(state, name)?;
+    let kind = &self.properties[position].kind;
+    kind.value_at((offset / stride) % kind.value_count())
+  }
+
+  //Returns `state` with property `name` set to `value`
+  pub fn with_property(&self, state: u16, name: &str, value: &PropertyValue) -> Option<u16> {
+    let (position, stride, offset) = self.locate_property(state, name)?;
+    let kind = &self.properties[position].kind;
+    let old = (offset / stride) % kind.value_count();
+    let new = kind.index_of(value)?;
+    Some(self.state_base? + (offset - old * stride + new * stride) as u16)
+  }
+
+  //Facing of the first `facing` property, used to rotate the block while meshing
+  pub fn facing(&self, state: u16) -> Facing {
+    self.properties.iter()
+      .find(|property| property.kind == PropertyKind::Facing)
+      .and_then(|property| self.get_property(state, &property.name))
+      .map_or(Facing::default(), |value| match value {
+        PropertyValue::Facing(facing) => facing,
+        _ => unreachable!()
+      })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+  InvalidKey,
+  DuplicateKey(String),
+  InvalidTextureIndex { face: CubeFace, index: usize },
+  MissingTextures,
+  InvalidProperty(String),
+  InvalidLightEmission(u8),
+  TooManyStates,
+  AirNotFirst,
+}
+impl fmt::Display for RegisterError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidKey => write!(f, "Invalid or empty block key"),
+      Self::DuplicateKey(key) => write!(f, "Block with key \"{}\" is already registered", key),
+      Self::InvalidTextureIndex { face, index } => write!(f, "Invalid texture index {} for face {:?}", index, face),
+      Self::MissingTextures => write!(f, "Visible blocks must have at least one texture"),
+      Self::InvalidProperty(name) => write!(f, "Property \"{}\" is invalid or duplicated", name),
+      Self::InvalidLightEmission(level) => write!(f, "Invalid light emission {} (max {})", level, MAX_LIGHT_LEVEL),
+      Self::TooManyStates => write!(f, "Too many block states (max {})", MAX_STATES),
+      Self::AirNotFirst => write!(f, "The first registered block must be air"),
+    }
+  }
+}
+impl std::error::Error for RegisterError {}
+
+#[derive(Default, Clone)]
+pub struct BlockTypeManager {
+  pub block_types: Vec<BlockMetadata>,
+  block_map: HashMap<String, usize>,
+  //State id -> block index
+  state_map: Vec<u16>,
+}
+impl BlockTypeManager {
+  pub fn register_multiple<const SIZE: usize>(&mut self, blocks: [BlockMetadata; SIZE]) {
+    for block in blocks { self.register(block); }
+  }
+
+  pub fn register_multiple_vec(&mut self, blocks: Vec<BlockMetadata>) {
+    for block in blocks { self.register(block); }
+  }
+
+  pub fn register(&mut self, block: BlockMetadata) -> usize {
+    self.try_register(block).unwrap_or_else(|error| panic!("Failed to register block: {}", error))
+  }
+
+  pub fn check(&self, block: &BlockMetadata) -> Result<(), RegisterError> {
+    self.check_at(block, self.state_map.len())
+  }
+
+  //`state_base` is the first state id the block would get
+  fn check_at(&self, block: &BlockMetadata, state_base: usize) -> Result<(), RegisterError> {
+    if &block.key[..] == INVALID_KEY || block.key.len() == 0 {
+      return Err(RegisterError::InvalidKey);
+    }
+    //Block::AIR is state 0
+    if state_base == 0 && !block.is_air() {
+      return Err(RegisterError::AirNotFirst);
+    }
+    if self.block_map.contains_key(&block.key) {
+      return Err(RegisterError::DuplicateKey(block.key.clone()));
+    }
+    if block.textures.len() > 0 {
+      for face in CubeFace::ALL {
+        let index = block.face_textures[face as usize];
+        if index >= block.textures.len() {
+          return Err(RegisterError::InvalidTextureIndex { face, index });
+        }
+      }
+    } else if !block.is_air() && block.shape != BlockShape::None {
+      return Err(RegisterError::MissingTextures);
+    }
+    for (position, property) in block.properties.iter().enumerate() {
+      let duplicate = block.properties[..position].iter().any(|x| x.name == property.name);
+      if duplicate || property.name.is_empty() || property.kind.value_count() == 0 {
+        return Err(RegisterError::InvalidProperty(property.name.clone()));
+      }
+    }
+    if block.light_emission > MAX_LIGHT_LEVEL {
+      return Err(RegisterError::InvalidLightEmission(block.light_emission));
+    }
+    if state_base + block.state_count() > MAX_STATES {
+      return Err(RegisterError::TooManyStates);
+    }
+    Ok(())
+  }
+
+  pub fn try_register(&mut self, mut block: BlockMetadata) -> Result<usize, RegisterError> {
+    self.check(&block)?;
+    let index = self.block_types.len();
+    block.index = Some(index);
+    block.state_base = Some(self.state_map.len() as u16);
+    self.state_map.extend(std::iter::repeat(index as u16).take(block.state_count()));
+    self.block_map.insert(block.key.clone(), index);
+    self.block_types.push(block);
+    Ok(index)
+  }
+
+  //Either registers all of the blocks or none of them
+  pub fn try_register_multiple(&mut self, blocks: impl IntoIterator<Item = BlockMetadata>) -> Result<(), RegisterError> {
+    let blocks: Vec<BlockMetadata> = blocks.into_iter().collect();
+    let mut keys = HashSet::default();
+    let mut states = self.state_map.len();
+    for block in &blocks {
+      self.check_at(block, states)?;
+      if !keys.insert(&block.key[..]) {
+        return Err(RegisterError::DuplicateKey(block.key.clone()));
+      }
+      states += block.state_count();
+    }
+    for block in blocks {
+      self.try_register(block)?;
+    }
+    Ok(())
+  }
+
+  pub fn get_by_index(&self, index: usize) -> Option<&BlockMetadata> {
+    Some(self.block_types.get(index)?)
+  }
+  pub fn get_by_key(&self, key: &str) -> Option<&BlockMetadata> {
+    Some(&self.block_types[*self.block_map.get(key)?])
+  }
+  pub fn get_by_state(&self, state: u16) -> Option<&BlockMetadata> {
+    Some(&self.block_types[*self.state_map.get(state as usize)? as usize])
+  }
+
+  //Block keys and state counts ordered by their index, sent to clients so they can remap state ids
+  pub fn palette(&self) -> Vec<PaletteEntry> {
+    self.block_types.iter().map(|block| PaletteEntry {
+      key: block.key.clone(),
+      states: block.state_count() as u32,
+    }).collect()
+  }
+
+  //Builds a table that maps state ids from a remote palette to local state ids
+  //Returns the list of missing or incompatible keys if the palettes don't match
+  pub fn remap_table(&self, palette: &[PaletteEntry]) -> Result<Vec<u16>, Vec<String>> {
+    let mut table = Vec::new();
+    let mut missing = Vec::new();
+    for entry in palette {
+      match self.get_by_key(&entry.key) {
+        Some(block) if block.state_count() == entry.states as usize => {
+          let base = block.default_state();
+          table.extend((0..entry.states).map(|state| base + state as u16));
+        },
+        Some(_) => missing.push(format!("{} (state mismatch)", entry.key)),
+        None => missing.push(entry.key.clone()),
+      }
+    }
+    match missing.is_empty() {
+      true => Ok(table),
+      false => Err(missing),
+    }
+  }
+
+  pub fn state_amount(&self) -> usize {
+    self.state_map.len()
+  }
+
+  //TODO Rename
+  pub fn amount(&self) -> usize {
+    self.block_types.len()
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaletteEntry {
+  pub key: String,
+  pub states: u32,
+}
@@ -0,0 +1,80 @@
+use serde::{Serialize, Deserialize};
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Facing {
+  North = 0,
+  East  = 1,
+  South = 2,
+  West  = 3,
+}
+impl Default for Facing {
+  fn default() -> Self { Self::North }
+}
+impl Facing {
+  pub const ALL: [Facing; 4] = [Facing::North, Facing::East, Facing::South, Facing::West];
+  //Amount of clockwise quarter turns (looking from above) from North
+  #[inline] pub fn rotation(self) -> usize { self as usize }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PropertyKind {
+  Bool,
+  Int { min: u8, max: u8 },
+  Enum { values: Vec<String> },
+  Facing,
+}
+impl PropertyKind {
+  pub fn value_count(&self) -> usize {
+    match self {
+      Self::Bool => 2,
+      Self::Int { min, max } => match min <= max {
+        true => (max - min) as usize + 1,
+        false => 0
+      },
+      Self::Enum { values } => values.len(),
+      Self::Facing => Facing::ALL.len(),
+    }
+  }
+
+  pub fn value_at(&self, index: usize) -> Option<PropertyValue> {
+    if index >= self.value_count() {
+      return None;
+    }
+    Some(match self {
+      Self::Bool => PropertyValue::Bool(index != 0),
+      Self::Int { min, .. } => PropertyValue::Int(min + index as u8),
+      Self::Enum { values } => PropertyValue::Enum(values[index].clone()),
+      Self::Facing => PropertyValue::Facing(Facing::ALL[index]),
+    })
+  }
+
+  pub fn index_of(&self, value: &PropertyValue) -> Option<usize> {
+    match (self, value) {
+      (Self::Bool, PropertyValue::Bool(value)) => Some(*value as usize),
+      (Self::Int { min, max }, PropertyValue::Int(value)) => {
+        (*min..=*max).contains(value).then(|| (value - min) as usize)
+      },
+      (Self::Enum { values }, PropertyValue::Enum(value)) => values.iter().position(|x| x == value),
+      (Self::Facing, PropertyValue::Facing(value)) => Some(value.rotation()),
+      _ => None
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PropertyValue {
+  Bool(bool),
+  Int(u8),
+  Enum(String),
+  Facing(Facing),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct BlockProperty {
+  pub name: String,
+  #[serde(flatten)]
+  pub kind: PropertyKind,
+}
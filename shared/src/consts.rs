@@ -1,19 +1,38 @@
 pub const CHUNK_SIZE: usize = 16;
 pub const CHUNK_HEIGHT: usize = 256;
+pub const SECTION_HEIGHT: usize = 16;
+pub const CHUNK_SECTIONS: usize = CHUNK_HEIGHT / SECTION_HEIGHT;
+pub const SECTION_VOLUME: usize = CHUNK_SIZE * SECTION_HEIGHT * CHUNK_SIZE;
+
+//Light levels range from 0 (dark) to MAX_LIGHT_LEVEL (full sky light)
+pub const MAX_LIGHT_LEVEL: u8 = 15;
 
 pub const DEFAULT_CLIENT_VIEW_DIST: usize = 6;
-//currently not used
+//Upper bound for view distances requested by clients
 pub const MAX_MP_VIEW_DIST: usize = 32;
+//Max distance between a player and a chunk it can request
 pub const MAX_MP_REQ_DIST: usize = MAX_MP_VIEW_DIST + 2;
 
+//Max distance between the player and the center of a block it's interacting with
+pub const REACH_DISTANCE: f32 = 5.;
+
 pub const DEFAULT_PORT: u16 = 12478;
 pub const PROTOCOL_ID: u64 = 5;
 pub const MAX_CLIENTS: usize = 64;
+//Server updates per second, player syncs are timestamped with the server tick
+pub const SERVER_TICK_RATE: u32 = 60;
 
 pub const CHANNEL_RELIABLE: u8 = 0;
 pub const CHANNEL_UNRELIABLE: u8 = 1;
+//Reliable and ordered, used for chunk data and everything that has to stay in order with it (unloads, block updates)
 pub const CHANNEL_BLOCK: u8 = 2;
 
+//Compressed chunks with a unique block in every position stay well below this
+pub const MAX_CHUNK_MESSAGE_SIZE: u64 = 256 * 1024;
+//A client can't be owed more than every chunk in its view area, plus an unload message for each of them
+//Block updates are small and get sent quickly, but still need some room on top of that
+pub const CHUNK_CHANNEL_QUEUE_SIZE: usize = 2 * (2 * MAX_MP_VIEW_DIST + 1) * (2 * MAX_MP_VIEW_DIST + 1) + 1024;
+
 pub const MIN_NAME_LEN: usize = 3;
 pub const MAX_NAME_LEN: usize = 24;
 pub const BANNED_NAMES: &[(&str, &str)] = &[
@@ -45,6 +64,15 @@ mod static_stuff {
     regexes
   });
 
+  fn chunk_channel_config() -> BlockChannelConfig {
+    BlockChannelConfig {
+      packet_budget: 32 * 1024,
+      max_message_size: MAX_CHUNK_MESSAGE_SIZE,
+      message_send_queue_size: CHUNK_CHANNEL_QUEUE_SIZE,
+      ..Default::default()
+    }
+  }
+
   #[inline(always)]
   pub fn renet_connection_config() -> RenetConnectionConfig {
     RenetConnectionConfig {
@@ -60,7 +88,7 @@ mod static_stuff {
           max_message_size: u64::MAX,
           ..Default::default()
         }),
-        ChannelConfig::Block(BlockChannelConfig::default()),
+        ChannelConfig::Block(chunk_channel_config()),
       ],
       receive_channels_config: vec![
         ChannelConfig::Reliable(ReliableChannelConfig {
@@ -73,7 +101,7 @@ mod static_stuff {
           max_message_size: u64::MAX,
           ..Default::default()
         }),
-        ChannelConfig::Block(BlockChannelConfig::default()),
+        ChannelConfig::Block(chunk_channel_config()),
       ],
       ..Default::default()
     }
@@ -3,3 +3,4 @@ pub mod types;
 pub mod messages;
 pub mod blocks;
 pub mod utils;
+pub mod physics;
@@ -1,9 +1,11 @@
 use serde::{Deserialize, Serialize};
 use bevy::prelude::Vec3;
+use crate::blocks::PaletteEntry;
 use crate::types::{
+  block::Block,
   chunk::CompressedChunkData,
   chat::ChatMessage,
-  player::PlayerInitData
+  player::{PlayerInitData, Orientation},
 };
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -19,21 +21,48 @@ pub enum ServerToClientMessages {
     self_init: PlayerInitData,
     player_init: Vec<(u64, PlayerInitData)>,
     chat_messages: Vec<ChatMessage>,
+    block_palette: Vec<PaletteEntry>,
   },
+  //Sent over the unreliable channel, `tick` is the server tick the state was accepted on
   PlayerSync {
     id: u64,
-    new_pos: Vec3
+    new_pos: Vec3,
+    orientation: Orientation,
+    tick: u64,
   },
+  //ChunkData and UnloadChunk are sent over CHANNEL_BLOCK, so they always arrive in order
   ChunkData {
     data: CompressedChunkData,
     position: (i64, i64)
   },
+  //The chunk left the view distance of the player, the client should drop it
+  UnloadChunk {
+    position: (i64, i64)
+  },
+  //View distance the server is going to use for the client, bounded by MAX_MP_VIEW_DIST
+  ViewDistance {
+    distance: usize
+  },
+  //Sent to every client that has the chunk loaded, over CHANNEL_BLOCK like the chunk data
+  BlockUpdate {
+    position: (i64, i64, i64),
+    block: Block
+  },
+  //Sent when the server rejects player movement, the client has to move back to `position`
+  PositionCorrection {
+    position: Vec3
+  },
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 #[non_exhaustive]
 pub enum ClientToServerMessages {
-  PlayerMove { new_pos: Vec3 },
+  //`sequence` increases with every move, older moves that arrive late are dropped
+  PlayerMove { new_pos: Vec3, orientation: Orientation, sequence: u32 },
   ChatMessage { message: String },
+  //Asks the server to send a chunk again, chunks around the player are sent automatically
   ChunkRequest { x: i64, y: i64 },
+  SetViewDistance { distance: usize },
+  BreakBlock { position: (i64, i64, i64) },
+  PlaceBlock { position: (i64, i64, i64), block: Block },
 }
@@ -0,0 +1,181 @@
+use bevy::prelude::*;
+
+//Player collision box, relative to the position of the feet
+pub const PLAYER_WIDTH: f32 = 0.6;
+pub const PLAYER_HEIGHT: f32 = 1.8;
+//Height of the camera above the feet, player transforms are at eye level
+pub const PLAYER_EYE_HEIGHT: f32 = 1.62;
+
+pub const GRAVITY: f32 = 32.;
+pub const TERMINAL_VELOCITY: f32 = 60.;
+//Enough to jump on top of a single block
+pub const JUMP_VELOCITY: f32 = 9.;
+pub const WALK_SPEED: f32 = 4.3;
+pub const FLY_SPEED: f32 = 12.;
+//Players walk up slabs without jumping, full blocks still have to be jumped on
+pub const STEP_HEIGHT: f32 = 0.6;
+
+//Keeps boxes that touch a block face from counting as overlapping it
+const EPSILON: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+  pub min: Vec3,
+  pub max: Vec3,
+}
+impl Aabb {
+  pub fn new(min: Vec3, max: Vec3) -> Self {
+    Self { min, max }
+  }
+
+  //Collision box of a player standing at `feet`
+  pub fn player(feet: Vec3) -> Self {
+    let half_width = PLAYER_WIDTH / 2.;
+    Self {
+      min: feet - Vec3::new(half_width, 0., half_width),
+      max: feet + Vec3::new(half_width, PLAYER_HEIGHT, half_width),
+    }
+  }
+
+  pub fn translated(self, offset: Vec3) -> Self {
+    Self {
+      min: self.min + offset,
+      max: self.max + offset,
+    }
+  }
+
+  //Blocks the box overlaps along `axis`, blocks that are only touched don't count
+  fn block_range(&self, axis: usize) -> std::ops::RangeInclusive<i64> {
+    ((self.min[axis] + EPSILON).floor() as i64)..=((self.max[axis] - EPSILON).floor() as i64)
+  }
+
+  //Every block the box overlaps
+  pub fn blocks(&self) -> impl Iterator<Item = (i64, i64, i64)> {
+    let (xs, ys, zs) = (self.block_range(0), self.block_range(1), self.block_range(2));
+    xs.flat_map(move |x| {
+      let zs = zs.clone();
+      ys.clone().flat_map(move |y| zs.clone().map(move |z| (x, y, z)))
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MoveResult {
+  //How far the box actually moved
+  pub movement: Vec3,
+  //Axes on which the movement was stopped by a block
+  pub collisions: [bool; 3],
+  pub on_ground: bool,
+}
+
+//Moves the box along a single axis until it hits a solid block
+//Blocks the box is already overlapping are ignored, so it can't get stuck inside of them
+//Returns the distance it moved and whether it hit something
+fn sweep_axis(aabb: &Aabb, axis: usize, distance: f32, is_solid: &impl Fn((i64, i64, i64)) -> bool) -> (f32, bool) {
+  if distance == 0. {
+    return (0., false);
+  }
+  let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+  let layer_blocked = |layer: i64| {
+    aabb.block_range(a).any(|i| aabb.block_range(b).any(|j| {
+      let mut position = [0; 3];
+      position[axis] = layer;
+      position[a] = i;
+      position[b] = j;
+      is_solid((position[0], position[1], position[2]))
+    }))
+  };
+  if distance > 0. {
+    let first = (aabb.max[axis] - EPSILON).floor() as i64 + 1;
+    let last = (aabb.max[axis] + distance - EPSILON).floor() as i64;
+    for layer in first..=last {
+      if layer_blocked(layer) {
+        return ((layer as f32 - aabb.max[axis]).max(0.), true);
+      }
+    }
+  } else {
+    let first = (aabb.min[axis] + EPSILON).floor() as i64 - 1;
+    let last = (aabb.min[axis] + distance + EPSILON).floor() as i64;
+    for layer in (last..=first).rev() {
+      if layer_blocked(layer) {
+        return (((layer + 1) as f32 - aabb.min[axis]).min(0.), true);
+      }
+    }
+  }
+  (distance, false)
+}
+
+//Moves the box through the world one axis at a time, vertical movement goes first
+pub fn sweep(aabb: Aabb, movement: Vec3, is_solid: &impl Fn((i64, i64, i64)) -> bool) -> MoveResult {
+  let mut aabb = aabb;
+  let mut result = MoveResult::default();
+  for axis in [1, 0, 2] {
+    let (distance, collided) = sweep_axis(&aabb, axis, movement[axis], is_solid);
+    let mut offset = Vec3::ZERO;
+    offset[axis] = distance;
+    aabb = aabb.translated(offset);
+    result.movement[axis] = distance;
+    result.collisions[axis] = collided;
+  }
+  result.on_ground = result.collisions[1] && movement.y < 0.;
+  result
+}
+
+//Moves a player standing at `feet`, stepping up blocks if it walks into them while on the ground
+pub fn move_player(feet: Vec3, movement: Vec3, on_ground: bool, is_solid: &impl Fn((i64, i64, i64)) -> bool) -> MoveResult {
+  let aabb = Aabb::player(feet);
+  let result = sweep(aabb, movement, is_solid);
+  if !(on_ground && (result.collisions[0] || result.collisions[2])) {
+    return result;
+  }
+
+  //Try the same horizontal movement from a bit higher up, then go back down
+  let horizontal = Vec3::new(movement.x, 0., movement.z);
+  let up = sweep(aabb, Vec3::Y * STEP_HEIGHT, is_solid);
+  let forward = sweep(aabb.translated(up.movement), horizontal, is_solid);
+  let down = sweep(aabb.translated(up.movement + forward.movement), -up.movement, is_solid);
+  let distance = |movement: Vec3| movement.x * movement.x + movement.z * movement.z;
+  if distance(forward.movement) <= distance(result.movement) + EPSILON {
+    return result;
+  }
+  MoveResult {
+    movement: up.movement + forward.movement + down.movement,
+    collisions: [forward.collisions[0], down.collisions[1], forward.collisions[2]],
+    on_ground: down.on_ground,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TOLERANCE: f32 = 1e-3;
+
+  #[test]
+  fn full_block_wall_stops_walking() {
+    //Floor with a single block high wall at x = 1
+    let is_solid = |(x, y, _): (i64, i64, i64)| y < 0 || (x == 1 && y == 0);
+    let result = move_player(Vec3::new(0.5, 0., 0.5), Vec3::X, true, &is_solid);
+    assert!(result.collisions[0]);
+    assert!((result.movement.x - (1. - PLAYER_WIDTH) / 2.).abs() < TOLERANCE);
+    assert!(result.movement.y.abs() < TOLERANCE);
+  }
+
+  #[test]
+  fn slab_height_step_is_climbed() {
+    //Player stands half a block below the top of the step, like on a slab next to a full block
+    let is_solid = |(x, y, _): (i64, i64, i64)| x >= 1 && y == 0;
+    let result = move_player(Vec3::new(0.5, 0.5, 0.5), Vec3::X, true, &is_solid);
+    assert!(!result.collisions[0]);
+    assert!(result.on_ground);
+    assert!((result.movement.x - 1.).abs() < TOLERANCE);
+    assert!((result.movement.y - 0.5).abs() < TOLERANCE);
+  }
+
+  #[test]
+  fn step_needs_ground() {
+    let is_solid = |(x, y, _): (i64, i64, i64)| x >= 1 && y == 0;
+    let result = move_player(Vec3::new(0.5, 0.5, 0.5), Vec3::X, false, &is_solid);
+    assert!(result.collisions[0]);
+  }
+}
@@ -1,6 +1,11 @@
 use serde::{Serialize, Deserialize};
 
+//Runtime state id, see BlockTypeManager::get_by_state
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Block {
-  pub block_type: u16
+  pub state: u16
+}
+impl Block {
+  //BlockTypeManager refuses to register anything but air first
+  pub const AIR: Self = Self { state: 0 };
 }
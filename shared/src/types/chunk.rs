@@ -1,10 +1,15 @@
 use bevy::prelude::*;
 use bevy::utils::HashMap;
-use lz4_flex::{compress_prepend_size, decompress_size_prepended};
-use serde::{Serialize, Deserialize};
-use serde_with::serde_as;
-use super::block::Block;
-use crate::consts::{CHUNK_SIZE, CHUNK_HEIGHT};
+use lz4_flex::{compress_prepend_size, decompress_size_prepended, block::DecompressError};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::Error as _};
+use std::{fmt, sync::Arc};
+use super::{block::Block, palette::PalettedContainer};
+use crate::consts::{CHUNK_SIZE, CHUNK_HEIGHT, SECTION_HEIGHT, CHUNK_SECTIONS, SECTION_VOLUME};
+
+//Bump this every time the layout of ChunkData changes
+pub const CHUNK_FORMAT_VERSION: u16 = 1;
+//Way more than a fully populated chunk with unique blocks everywhere
+const MAX_DECOMPRESSED_CHUNK_SIZE: usize = 4 * 1024 * 1024;
 
 #[derive(Component, Clone, Copy)]
 pub struct Chunk;
@@ -16,6 +21,19 @@ impl ChunkPosition {
   #[inline] pub fn x(&self) -> i64 { self.0 }
   #[inline] pub fn y(&self) -> i64 { self.1 }
   #[inline] pub fn xy(&self) -> (i64, i64) { (self.0, self.1) }
+
+  //Splits world block coordinates into the chunk position and coordinates inside of that chunk
+  //Returns None if y is outside of the world
+  pub fn from_block(x: i64, y: i64, z: i64) -> Option<(Self, (usize, usize, usize))> {
+    if !(0..CHUNK_HEIGHT as i64).contains(&y) {
+      return None;
+    }
+    const SIZE: i64 = CHUNK_SIZE as i64;
+    Some((
+      Self(x.div_euclid(SIZE), z.div_euclid(SIZE)),
+      (x.rem_euclid(SIZE) as usize, y as usize, z.rem_euclid(SIZE) as usize)
+    ))
+  }
 }
 #[derive(Component, Clone)] 
 pub struct ChunkDataComponent(pub ChunkData);
@@ -35,43 +53,205 @@ impl ChunkMap {
   #[inline] pub fn exists(&self, pos: ChunkPosition) -> bool {
     self.0.contains_key(&(pos.0, pos.1))
   }
+  #[inline] pub fn len(&self) -> usize {
+    self.0.len()
+  }
+  #[inline] pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
 }
 
-#[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
-pub struct ChunkData (
-  #[serde_as(as = "Box<[[[_; CHUNK_SIZE]; CHUNK_HEIGHT]; CHUNK_SIZE]>")]
-  pub Box<[[[Block; CHUNK_SIZE]; CHUNK_HEIGHT]; CHUNK_SIZE]>
-);
+//A 16x16x16 part of the chunk
+//Blocks are stored in a palette-compressed container behind an Arc
+//Cloning is cheap, the container is only copied when a shared section is modified
+#[derive(Clone)]
+pub struct ChunkSection {
+  blocks: Arc<PalettedContainer>,
+  non_air: u16,
+}
+impl ChunkSection {
+  #[inline]
+  pub fn new() -> Self {
+    Self {
+      blocks: Arc::new(PalettedContainer::new(SECTION_VOLUME, Block::AIR)),
+      non_air: 0,
+    }
+  }
+
+  #[inline] fn index(x: usize, y: usize, z: usize) -> usize {
+    debug_assert!(x < CHUNK_SIZE && y < SECTION_HEIGHT && z < CHUNK_SIZE);
+    (x * SECTION_HEIGHT + y) * CHUNK_SIZE + z
+  }
+
+  #[inline] pub fn get(&self, x: usize, y: usize, z: usize) -> Block {
+    self.blocks.get(Self::index(x, y, z))
+  }
+
+  pub fn set(&mut self, x: usize, y: usize, z: usize, block: Block) {
+    let index = Self::index(x, y, z);
+    let old = self.blocks.get(index);
+    if old == block { return }
+    Arc::make_mut(&mut self.blocks).set(index, block);
+    if old == Block::AIR {
+      self.non_air += 1;
+    } else if block == Block::AIR {
+      self.non_air -= 1;
+    }
+  }
+
+  #[inline] pub fn is_empty(&self) -> bool {
+    self.non_air == 0
+  }
+
+  #[inline] pub fn container(&self) -> &PalettedContainer {
+    &self.blocks
+  }
+
+  fn recount(&mut self) {
+    self.non_air = (0..SECTION_VOLUME).filter(|&index| self.blocks.get(index) != Block::AIR).count() as u16;
+  }
+}
+impl Serialize for ChunkSection {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    self.blocks.serialize(serializer)
+  }
+}
+impl<'de> Deserialize<'de> for ChunkSection {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let container = PalettedContainer::deserialize(deserializer)?;
+    if container.len() != SECTION_VOLUME {
+      return Err(D::Error::custom("Invalid section size"));
+    }
+    let mut section = Self { blocks: Arc::new(container), non_air: 0 };
+    section.recount();
+    Ok(section)
+  }
+}
+
+//All-air sections are stored as None
+#[derive(Clone, Serialize)]
+pub struct ChunkData {
+  sections: [Option<ChunkSection>; CHUNK_SECTIONS]
+}
 impl ChunkData {
   #[inline]
   pub fn new() -> Self {
-    Self (Box::new([[[Block{block_type: 0}; CHUNK_SIZE]; CHUNK_HEIGHT]; CHUNK_SIZE]))
+    Self { sections: Default::default() }
+  }
+
+  #[inline] pub fn get(&self, x: usize, y: usize, z: usize) -> Block {
+    match &self.sections[y / SECTION_HEIGHT] {
+      Some(section) => section.get(x, y % SECTION_HEIGHT, z),
+      None => Block::AIR
+    }
+  }
+
+  pub fn set(&mut self, x: usize, y: usize, z: usize, block: Block) {
+    let slot = &mut self.sections[y / SECTION_HEIGHT];
+    if slot.is_none() {
+      if block == Block::AIR { return }
+      *slot = Some(ChunkSection::new());
+    }
+    let section = slot.as_mut().unwrap();
+    section.set(x, y % SECTION_HEIGHT, z, block);
+    if section.is_empty() {
+      *slot = None;
+    }
+  }
+
+  #[inline] pub fn section(&self, index: usize) -> Option<&ChunkSection> {
+    self.sections[index].as_ref()
+  }
+
+  //Replaces every state id with table[state], unknown states become air
+  pub fn remap(&mut self, table: &[u16]) {
+    for slot in self.sections.iter_mut() {
+      if let Some(section) = slot {
+        Arc::make_mut(&mut section.blocks).map_palette(|block| Block {
+          state: table.get(block.state as usize).copied().unwrap_or(0)
+        });
+        section.recount();
+        if section.is_empty() {
+          *slot = None;
+        }
+      }
+    }
   }
 }
+impl<'de> Deserialize<'de> for ChunkData {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let mut sections = <[Option<ChunkSection>; CHUNK_SECTIONS]>::deserialize(deserializer)?;
+    for slot in sections.iter_mut() {
+      if slot.as_ref().map_or(false, |section| section.is_empty()) {
+        *slot = None;
+      }
+    }
+    Ok(Self { sections })
+  }
+}
+
+#[derive(Debug)]
+pub enum ChunkDecodeError {
+  TooShort,
+  UnsupportedVersion(u16),
+  TooLarge(usize),
+  Decompress(DecompressError),
+  Deserialize(bincode::Error),
+}
+impl fmt::Display for ChunkDecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::TooShort => write!(f, "Chunk data is too short"),
+      Self::UnsupportedVersion(version) => write!(f, "Unsupported chunk format version {} (expected {})", version, CHUNK_FORMAT_VERSION),
+      Self::TooLarge(size) => write!(f, "Decompressed chunk size is too large ({} bytes)", size),
+      Self::Decompress(error) => write!(f, "Failed to decompress chunk data: {}", error),
+      Self::Deserialize(error) => write!(f, "Failed to deserialize chunk data: {}", error),
+    }
+  }
+}
+impl std::error::Error for ChunkDecodeError {}
 
+//Layout: [format version: u16 LE][uncompressed size: u32 LE][LZ4 block]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CompressedChunkData(pub Vec<u8>);
+impl CompressedChunkData {
+  pub fn version(&self) -> Option<u16> {
+    Some(u16::from_le_bytes(self.0.get(0..2)?.try_into().ok()?))
+  }
+}
 impl From<&ChunkData> for CompressedChunkData {
   #[inline] fn from(chunk_data: &ChunkData) -> Self {
-    let data = bincode::serialize(&chunk_data).unwrap();
-    let cumpressed = compress_prepend_size(&data[..]);
+    let data = bincode::serialize(&chunk_data).expect("Failed to serialize chunk data");
+    let mut cumpressed = CHUNK_FORMAT_VERSION.to_le_bytes().to_vec();
+    cumpressed.extend_from_slice(&compress_prepend_size(&data[..]));
     Self(cumpressed)
   }
 }
-impl Into<ChunkData> for &CompressedChunkData {
-  #[inline] fn into(self) -> ChunkData {
-    let decumpressed = decompress_size_prepended(&self.0[..]).unwrap();
-    bincode::deserialize(&decumpressed[..]).unwrap()
-  }
-}
 impl From<ChunkData> for CompressedChunkData {
   #[inline] fn from(chunk_data: ChunkData) -> Self {
     (&chunk_data).into()
   }
 }
-impl Into<ChunkData> for CompressedChunkData {
-  #[inline] fn into(self) -> ChunkData {
-    (&self).into()
+impl TryFrom<&CompressedChunkData> for ChunkData {
+  type Error = ChunkDecodeError;
+  fn try_from(compressed: &CompressedChunkData) -> Result<Self, Self::Error> {
+    let version = compressed.version().ok_or(ChunkDecodeError::TooShort)?;
+    if version != CHUNK_FORMAT_VERSION {
+      return Err(ChunkDecodeError::UnsupportedVersion(version));
+    }
+    let payload = &compressed.0[2..];
+    //Check the size before decompressing, a corrupted header could make us allocate gigabytes
+    let size = u32::from_le_bytes(payload.get(0..4).ok_or(ChunkDecodeError::TooShort)?.try_into().unwrap()) as usize;
+    if size > MAX_DECOMPRESSED_CHUNK_SIZE {
+      return Err(ChunkDecodeError::TooLarge(size));
+    }
+    let decumpressed = decompress_size_prepended(payload).map_err(ChunkDecodeError::Decompress)?;
+    bincode::deserialize(&decumpressed[..]).map_err(ChunkDecodeError::Deserialize)
+  }
+}
+impl TryFrom<CompressedChunkData> for ChunkData {
+  type Error = ChunkDecodeError;
+  #[inline] fn try_from(compressed: CompressedChunkData) -> Result<Self, Self::Error> {
+    (&compressed).try_into()
   }
 }
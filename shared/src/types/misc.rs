@@ -1,5 +1,8 @@
+use serde::{Serialize, Deserialize};
+
 #[repr(usize)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CubeFace {
   Top    = 0,
   Front  = 1,
@@ -8,3 +11,34 @@ pub enum CubeFace {
   Back   = 4,
   Bottom = 5,
 }
+impl CubeFace {
+  pub const ALL: [CubeFace; 6] = [
+    CubeFace::Top,
+    CubeFace::Front,
+    CubeFace::Left,
+    CubeFace::Right,
+    CubeFace::Back,
+    CubeFace::Bottom,
+  ];
+
+  //Offset to the neighbouring block this face is pointing towards
+  pub fn offset(self) -> (i8, i8, i8) {
+    match self {
+      CubeFace::Top    => (0, 1, 0),
+      CubeFace::Front  => (0, 0, -1),
+      CubeFace::Left   => (-1, 0, 0),
+      CubeFace::Right  => (1, 0, 0),
+      CubeFace::Back   => (0, 0, 1),
+      CubeFace::Bottom => (0, -1, 0),
+    }
+  }
+
+  //Rotates horizontal faces clockwise (looking from above) by `steps` quarter turns
+  pub fn rotate_y(self, steps: usize) -> Self {
+    const CYCLE: [CubeFace; 4] = [CubeFace::Front, CubeFace::Right, CubeFace::Back, CubeFace::Left];
+    match CYCLE.iter().position(|&face| face == self) {
+      Some(index) => CYCLE[(index + steps) % CYCLE.len()],
+      None => self
+    }
+  }
+}
@@ -3,6 +3,7 @@ pub mod block;
 pub mod chat;
 pub mod chunk;
 pub mod net;
+pub mod palette;
 pub mod player;
 //Re-export other types
 mod misc;
@@ -0,0 +1,246 @@
+use std::borrow::Cow;
+use serde::{Serialize, Serializer, Deserialize};
+use super::block::Block;
+use crate::consts::SECTION_VOLUME;
+
+const MAX_BITS: u8 = 16;
+//Containers only ever store chunk sections, deserialized ones can't be any larger
+const MAX_LEN: usize = SECTION_VOLUME;
+
+#[inline] fn bits_for(count: usize) -> u8 {
+  match count {
+    0 | 1 => 0,
+    _ => (usize::BITS - (count - 1).leading_zeros()) as u8
+  }
+}
+//None if the length is too large
+#[inline] fn words_for(len: usize, bits: u8) -> Option<usize> {
+  match bits {
+    0 => Some(0),
+    _ => {
+      let per_word = 64 / bits as usize;
+      Some(len.checked_add(per_word - 1)? / per_word)
+    }
+  }
+}
+
+//Stores `len` blocks as indices into a palette, packed into u64 words
+//Entries never span across two words
+//If only a single block type is stored, no data is allocated at all
+//The palette only grows while blocks are set, it's compacted when the container is serialized
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "RawPalettedContainer")]
+pub struct PalettedContainer {
+  len: usize,
+  bits: u8,
+  palette: Vec<Block>,
+  data: Vec<u64>,
+}
+impl PalettedContainer {
+  pub fn new(len: usize, value: Block) -> Self {
+    Self {
+      len,
+      bits: 0,
+      palette: vec![value],
+      data: Vec::new(),
+    }
+  }
+
+  #[inline] pub fn len(&self) -> usize { self.len }
+  #[inline] pub fn palette(&self) -> &[Block] { &self.palette }
+
+  #[inline] fn get_raw(&self, index: usize) -> usize {
+    if self.bits == 0 { return 0 }
+    let per_word = 64 / self.bits as usize;
+    let shift = (index % per_word) * self.bits as usize;
+    let mask = (1u64 << self.bits) - 1;
+    ((self.data[index / per_word] >> shift) & mask) as usize
+  }
+
+  #[inline] fn set_raw(&mut self, index: usize, value: usize) {
+    if self.bits == 0 { return }
+    let per_word = 64 / self.bits as usize;
+    let shift = (index % per_word) * self.bits as usize;
+    let mask = ((1u64 << self.bits) - 1) << shift;
+    let word = &mut self.data[index / per_word];
+    *word = (*word & !mask) | ((value as u64) << shift);
+  }
+
+  fn resize(&mut self, bits: u8) {
+    let mut resized = Self {
+      len: self.len,
+      bits,
+      palette: Vec::new(),
+      data: vec![0; words_for(self.len, bits).expect("Container too large")],
+    };
+    for index in 0..self.len {
+      resized.set_raw(index, self.get_raw(index));
+    }
+    self.bits = bits;
+    self.data = resized.data;
+  }
+
+  #[inline] pub fn get(&self, index: usize) -> Block {
+    assert!(index < self.len, "Index out of bounds");
+    self.palette[self.get_raw(index)]
+  }
+
+  pub fn set(&mut self, index: usize, value: Block) {
+    assert!(index < self.len, "Index out of bounds");
+    let palette_index = match self.palette.iter().position(|&x| x == value) {
+      Some(palette_index) => palette_index,
+      None => {
+        self.palette.push(value);
+        let bits = bits_for(self.palette.len());
+        if bits > self.bits {
+          self.resize(bits);
+        }
+        self.palette.len() - 1
+      }
+    };
+    self.set_raw(index, palette_index);
+  }
+
+  //Drops palette entries that are no longer used, merges duplicates and uses as few bits as possible
+  pub fn compacted(&self) -> Cow<Self> {
+    if self.len == 0 { return Cow::Borrowed(self) }
+    //Old palette index -> new palette index
+    let mut remap: Vec<Option<usize>> = vec![None; self.palette.len()];
+    let mut palette = Vec::new();
+    for index in 0..self.len {
+      let raw = self.get_raw(index);
+      if remap[raw].is_some() { continue }
+      let value = self.palette[raw];
+      remap[raw] = Some(match palette.iter().position(|&x| x == value) {
+        Some(palette_index) => palette_index,
+        None => {
+          palette.push(value);
+          palette.len() - 1
+        }
+      });
+    }
+    let bits = bits_for(palette.len());
+    if palette.len() == self.palette.len() && bits == self.bits {
+      return Cow::Borrowed(self);
+    }
+    let mut compacted = Self {
+      len: self.len,
+      bits,
+      palette,
+      data: vec![0; words_for(self.len, bits).expect("Container too large")],
+    };
+    for index in 0..self.len {
+      compacted.set_raw(index, remap[self.get_raw(index)].unwrap());
+    }
+    Cow::Owned(compacted)
+  }
+
+  //Applies `f` to every palette entry, which is much cheaper than touching every block
+  pub fn map_palette(&mut self, f: impl Fn(Block) -> Block) {
+    for entry in self.palette.iter_mut() {
+      *entry = f(*entry);
+    }
+  }
+}
+
+impl Serialize for PalettedContainer {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let compacted = self.compacted();
+    //Same layout as RawPalettedContainer
+    SerializedPalettedContainer {
+      len: compacted.len,
+      bits: compacted.bits,
+      palette: &compacted.palette,
+      data: &compacted.data,
+    }.serialize(serializer)
+  }
+}
+#[derive(Serialize)]
+struct SerializedPalettedContainer<'a> {
+  len: usize,
+  bits: u8,
+  palette: &'a [Block],
+  data: &'a [u64],
+}
+
+//Used to validate deserialized data, so get() can never panic on a corrupted container
+#[derive(Deserialize)]
+struct RawPalettedContainer {
+  len: usize,
+  bits: u8,
+  palette: Vec<Block>,
+  data: Vec<u64>,
+}
+impl TryFrom<RawPalettedContainer> for PalettedContainer {
+  type Error = &'static str;
+  fn try_from(raw: RawPalettedContainer) -> Result<Self, Self::Error> {
+    //Has to be checked first, everything below scales with the length
+    if raw.len > MAX_LEN {
+      return Err("Too many entries");
+    }
+    if raw.palette.is_empty() {
+      return Err("Empty palette");
+    }
+    if raw.bits > MAX_BITS || raw.bits < bits_for(raw.palette.len()) {
+      return Err("Invalid amount of bits per entry");
+    }
+    if Some(raw.data.len()) != words_for(raw.len, raw.bits) {
+      return Err("Invalid data length");
+    }
+    let container = Self {
+      len: raw.len,
+      bits: raw.bits,
+      palette: raw.palette,
+      data: raw.data,
+    };
+    if (0..container.len).any(|index| container.get_raw(index) >= container.palette.len()) {
+      return Err("Palette index out of bounds");
+    }
+    Ok(container)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STONE: Block = Block { state: 1 };
+  const DIRT: Block = Block { state: 2 };
+
+  fn round_trip(container: &PalettedContainer) -> PalettedContainer {
+    bincode::deserialize(&bincode::serialize(container).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn unused_entries_are_not_serialized() {
+    let mut container = PalettedContainer::new(MAX_LEN, Block::AIR);
+    container.set(0, STONE);
+    container.set(1, DIRT);
+    for index in 0..MAX_LEN {
+      container.set(index, STONE);
+    }
+    let loaded = round_trip(&container);
+    assert_eq!(loaded.palette(), &[STONE]);
+    assert!(loaded.data.is_empty());
+    assert!((0..MAX_LEN).all(|index| loaded.get(index) == STONE));
+  }
+
+  #[test]
+  fn duplicate_entries_are_merged() {
+    let mut container = PalettedContainer::new(MAX_LEN, Block::AIR);
+    container.set(0, STONE);
+    container.set(1, DIRT);
+    container.map_palette(|block| if block == DIRT { STONE } else { block });
+    let loaded = round_trip(&container);
+    assert_eq!(loaded.palette(), &[Block::AIR, STONE]);
+    assert_eq!(loaded.bits, 1);
+    assert_eq!((loaded.get(0), loaded.get(1), loaded.get(2)), (STONE, STONE, Block::AIR));
+  }
+
+  #[test]
+  fn compact_containers_are_not_copied() {
+    let mut container = PalettedContainer::new(MAX_LEN, Block::AIR);
+    container.set(0, STONE);
+    assert!(matches!(container.compacted(), Cow::Borrowed(_)));
+  }
+}
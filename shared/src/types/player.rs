@@ -1,11 +1,59 @@
 use bevy::prelude::*;
 use serde::{Serialize, Deserialize};
+use std::f32::consts::{PI, FRAC_PI_2};
 
 #[derive(Component, Debug, Clone)]
 pub struct Username(pub String);
 
+//Direction a player is looking in, in radians
+//Yaw turns around the Y axis, pitch looks up (positive) or down (negative)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Orientation {
+  pub yaw: f32,
+  pub pitch: f32,
+}
+impl Orientation {
+  pub fn new(yaw: f32, pitch: f32) -> Self {
+    Self { yaw, pitch }
+  }
+
+  pub fn from_rotation(rotation: Quat) -> Self {
+    let forward = rotation * -Vec3::Z;
+    Self {
+      yaw: (-forward.x).atan2(-forward.z),
+      pitch: forward.y.clamp(-1., 1.).asin(),
+    }
+  }
+
+  pub fn to_rotation(self) -> Quat {
+    Quat::from_axis_angle(Vec3::Y, self.yaw) * Quat::from_axis_angle(Vec3::X, self.pitch)
+  }
+
+  pub fn is_finite(&self) -> bool {
+    self.yaw.is_finite() && self.pitch.is_finite()
+  }
+
+  //Wraps the yaw to [-PI, PI) and limits the pitch to straight up/down
+  pub fn normalized(self) -> Self {
+    Self {
+      yaw: (self.yaw + PI).rem_euclid(2. * PI) - PI,
+      pitch: self.pitch.clamp(-FRAC_PI_2, FRAC_PI_2),
+    }
+  }
+
+  //Yaw is interpolated the short way around
+  pub fn lerp(self, other: Self, t: f32) -> Self {
+    let yaw_delta = (other.yaw - self.yaw + PI).rem_euclid(2. * PI) - PI;
+    Self {
+      yaw: self.yaw + yaw_delta * t,
+      pitch: self.pitch + (other.pitch - self.pitch) * t,
+    }.normalized()
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PlayerInitData {
   pub position: Vec3,
+  pub orientation: Orientation,
   pub username: String,
 }